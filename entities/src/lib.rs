@@ -1,4 +1,9 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.6
 
+pub mod api_key;
+pub mod audit_log;
+pub mod login_attempt;
 pub mod post;
+pub mod recovery_code;
 pub mod user;
+pub mod user_identity;
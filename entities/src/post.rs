@@ -2,18 +2,29 @@
 
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Deserialize, Serialize, ToSchema)]
 #[sea_orm(table_name = "post")]
+#[schema(as = Post)]
 pub struct Model {
     #[sea_orm(primary_key)]
-    #[serde(skip_deserializing)]
     pub id: i32,
     pub user_id: Option<i32>,
     pub slug: Option<String>,
     pub title: String,
     pub text: String,
     pub is_published: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub excerpt: Option<String>,
+    pub word_count: i32,
+    /// Estimated minutes to read.
+    pub reading_time: i32,
+    /// Bumped on every write, and sent as the `ETag`.
+    pub version: i32,
+    /// When the post was moved to the trash, `None` while it's live.
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub deleted_at: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
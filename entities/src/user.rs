@@ -11,15 +11,40 @@ pub struct Model {
     pub id: i32,
     pub username: String,
     pub email: String,
+    #[serde(skip)]
     pub password: String,
     pub is_active: bool,
     pub is_admin: bool,
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip)]
+    pub totp_last_step: Option<i64>,
+    pub display_name: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub bio: Option<String>,
+    pub website: Option<String>,
+    pub avatar_url: Option<String>,
+    #[sea_orm(column_type = "JsonBinary", nullable)]
+    pub social_links: Option<Json>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::api_key::Entity")]
+    ApiKey,
     #[sea_orm(has_many = "super::post::Entity")]
     Post,
+    #[sea_orm(has_many = "super::recovery_code::Entity")]
+    RecoveryCode,
+    #[sea_orm(has_many = "super::user_identity::Entity")]
+    UserIdentity,
+}
+
+impl Related<super::api_key::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ApiKey.def()
+    }
 }
 
 impl Related<super::post::Entity> for Entity {
@@ -28,4 +53,16 @@ impl Related<super::post::Entity> for Entity {
     }
 }
 
+impl Related<super::recovery_code::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::RecoveryCode.def()
+    }
+}
+
+impl Related<super::user_identity::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::UserIdentity.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
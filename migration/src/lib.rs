@@ -2,6 +2,18 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_user_table;
 mod m20220101_000002_create_post_table;
+mod m20220101_000003_add_user_unique_indexes;
+mod m20220101_000004_create_login_attempt_table;
+mod m20220101_000005_add_two_factor;
+mod m20220101_000006_create_api_key_table;
+mod m20220101_000007_create_user_identity_table;
+mod m20220101_000008_add_user_profile;
+mod m20220101_000009_add_user_profile_links;
+mod m20220101_000010_add_post_excerpt;
+mod m20220101_000011_add_post_version;
+mod m20220101_000012_add_post_deleted_at;
+mod m20220101_000013_create_audit_log_table;
+mod m20220101_000014_add_user_totp_last_step;
 
 pub struct Migrator;
 
@@ -11,6 +23,18 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20220101_000001_create_user_table::Migration),
             Box::new(m20220101_000002_create_post_table::Migration),
+            Box::new(m20220101_000003_add_user_unique_indexes::Migration),
+            Box::new(m20220101_000004_create_login_attempt_table::Migration),
+            Box::new(m20220101_000005_add_two_factor::Migration),
+            Box::new(m20220101_000006_create_api_key_table::Migration),
+            Box::new(m20220101_000007_create_user_identity_table::Migration),
+            Box::new(m20220101_000008_add_user_profile::Migration),
+            Box::new(m20220101_000009_add_user_profile_links::Migration),
+            Box::new(m20220101_000010_add_post_excerpt::Migration),
+            Box::new(m20220101_000011_add_post_version::Migration),
+            Box::new(m20220101_000012_add_post_deleted_at::Migration),
+            Box::new(m20220101_000013_create_audit_log_table::Migration),
+            Box::new(m20220101_000014_add_user_totp_last_step::Migration),
         ]
     }
 }
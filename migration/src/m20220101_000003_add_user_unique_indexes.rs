@@ -0,0 +1,66 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let backend = manager.get_database_backend();
+
+        // Existing duplicates would make the unique indexes fail to build, so the oldest
+        // account keeps its username/email and every later copy gets a suffixed username
+        // and a prefixed email, and is deactivated until an admin sorts it out.
+        db.execute(Statement::from_string(
+            backend,
+            r#"UPDATE "user" AS u
+               SET "username" = u."username" || '-' || u."id"
+               WHERE EXISTS (
+                   SELECT 1 FROM "user" AS o
+                   WHERE lower(o."username") = lower(u."username") AND o."id" < u."id"
+               )"#
+            .to_owned(),
+        ))
+        .await?;
+
+        db.execute(Statement::from_string(
+            backend,
+            r#"UPDATE "user" AS u
+               SET "email" = 'duplicate-' || u."id" || '+' || u."email", "is_active" = false
+               WHERE EXISTS (
+                   SELECT 1 FROM "user" AS o
+                   WHERE lower(o."email") = lower(u."email") AND o."id" < u."id"
+               )"#
+            .to_owned(),
+        ))
+        .await?;
+
+        // sea-query can't express functional indexes, so these are written out by hand.
+        db.execute(Statement::from_string(
+            backend,
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS "idx-user-username-lower" ON "user" (lower("username"))"#.to_owned(),
+        ))
+        .await?;
+
+        db.execute(Statement::from_string(
+            backend,
+            r#"CREATE UNIQUE INDEX IF NOT EXISTS "idx-user-email-lower" ON "user" (lower("email"))"#.to_owned(),
+        ))
+        .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // The duplicate cleanup in `up` is not reversed.
+        manager
+            .drop_index(Index::drop().name("idx-user-email-lower").to_owned())
+            .await?;
+
+        manager
+            .drop_index(Index::drop().name("idx-user-username-lower").to_owned())
+            .await
+    }
+}
@@ -0,0 +1,38 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(LoginAttempt::Table)
+                    .if_not_exists()
+                    .col(ColumnDef::new(LoginAttempt::Key).string().not_null().primary_key())
+                    .col(ColumnDef::new(LoginAttempt::Failures).integer().not_null())
+                    .col(ColumnDef::new(LoginAttempt::LastFailure).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(LoginAttempt::BlockedUntil).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(LoginAttempt::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub enum LoginAttempt {
+    Table,
+    Key,
+    Failures,
+    LastFailure,
+    BlockedUntil,
+}
@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(TwoFactor::TotpSecret).string())
+                    .add_column(ColumnDef::new(TwoFactor::TotpEnabled).boolean().not_null().default(false))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(RecoveryCode::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RecoveryCode::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(RecoveryCode::UserId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-recovery_code-user_id")
+                            .from(RecoveryCode::Table, RecoveryCode::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .col(ColumnDef::new(RecoveryCode::CodeHash).string().not_null())
+                    .col(ColumnDef::new(RecoveryCode::UsedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RecoveryCode::Table).to_owned())
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(TwoFactor::TotpSecret)
+                    .drop_column(TwoFactor::TotpEnabled)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Columns added to `user`.
+#[derive(Iden)]
+pub enum TwoFactor {
+    TotpSecret,
+    TotpEnabled,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub enum RecoveryCode {
+    Table,
+    Id,
+    UserId,
+    CodeHash,
+    UsedAt,
+}
@@ -0,0 +1,63 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ApiKey::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ApiKey::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ApiKey::UserId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-api_key-user_id")
+                            .from(ApiKey::Table, ApiKey::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .col(ColumnDef::new(ApiKey::Name).string().not_null())
+                    .col(ColumnDef::new(ApiKey::Prefix).string().not_null().unique_key())
+                    .col(ColumnDef::new(ApiKey::KeyHash).string().not_null())
+                    .col(ColumnDef::new(ApiKey::Scopes).string().not_null())
+                    .col(ColumnDef::new(ApiKey::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(ApiKey::LastUsedAt).timestamp_with_time_zone())
+                    .col(ColumnDef::new(ApiKey::RevokedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiKey::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub enum ApiKey {
+    Table,
+    Id,
+    UserId,
+    Name,
+    Prefix,
+    KeyHash,
+    Scopes,
+    CreatedAt,
+    LastUsedAt,
+    RevokedAt,
+}
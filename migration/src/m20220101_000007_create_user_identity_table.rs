@@ -0,0 +1,72 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(UserIdentity::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(UserIdentity::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(UserIdentity::UserId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-user_identity-user_id")
+                            .from(UserIdentity::Table, UserIdentity::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .col(ColumnDef::new(UserIdentity::Issuer).string().not_null())
+                    .col(ColumnDef::new(UserIdentity::Subject).string().not_null())
+                    .col(ColumnDef::new(UserIdentity::Email).string())
+                    .col(ColumnDef::new(UserIdentity::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(UserIdentity::LastLoginAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        // one account per identity at a given provider
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-user_identity-issuer-subject")
+                    .table(UserIdentity::Table)
+                    .col(UserIdentity::Issuer)
+                    .col(UserIdentity::Subject)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(UserIdentity::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub enum UserIdentity {
+    Table,
+    Id,
+    UserId,
+    Issuer,
+    Subject,
+    Email,
+    CreatedAt,
+    LastLoginAt,
+}
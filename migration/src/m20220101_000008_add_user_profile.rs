@@ -0,0 +1,40 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(Profile::DisplayName).string())
+                    .add_column(ColumnDef::new(Profile::Bio).text())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(Profile::DisplayName)
+                    .drop_column(Profile::Bio)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Columns added to `user`.
+#[derive(Iden)]
+pub enum Profile {
+    DisplayName,
+    Bio,
+}
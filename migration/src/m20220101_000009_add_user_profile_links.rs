@@ -0,0 +1,43 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(ProfileLinks::Website).string())
+                    .add_column(ColumnDef::new(ProfileLinks::AvatarUrl).string())
+                    .add_column(ColumnDef::new(ProfileLinks::SocialLinks).json_binary())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .drop_column(ProfileLinks::Website)
+                    .drop_column(ProfileLinks::AvatarUrl)
+                    .drop_column(ProfileLinks::SocialLinks)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Columns added to `user`.
+#[derive(Iden)]
+pub enum ProfileLinks {
+    Website,
+    AvatarUrl,
+    SocialLinks,
+}
@@ -0,0 +1,44 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000002_create_post_table::Post;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // existing posts keep a null excerpt until the app fills these in at startup
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Post::Table)
+                    .add_column(ColumnDef::new(PostSummary::Excerpt).text())
+                    .add_column(ColumnDef::new(PostSummary::WordCount).integer().not_null().default(0))
+                    .add_column(ColumnDef::new(PostSummary::ReadingTime).integer().not_null().default(0))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Post::Table)
+                    .drop_column(PostSummary::Excerpt)
+                    .drop_column(PostSummary::WordCount)
+                    .drop_column(PostSummary::ReadingTime)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Columns added to `post`.
+#[derive(Iden)]
+pub enum PostSummary {
+    Excerpt,
+    WordCount,
+    ReadingTime,
+}
@@ -0,0 +1,32 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000002_create_post_table::Post;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Post::Table)
+                    .add_column(ColumnDef::new(PostVersion::Version).integer().not_null().default(1))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(Table::alter().table(Post::Table).drop_column(PostVersion::Version).to_owned())
+            .await
+    }
+}
+
+/// Bumped on every write, for optimistic concurrency control.
+#[derive(Iden)]
+pub enum PostVersion {
+    Version,
+}
@@ -0,0 +1,32 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000002_create_post_table::Post;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Post::Table)
+                    .add_column(ColumnDef::new(PostTrash::DeletedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(Table::alter().table(Post::Table).drop_column(PostTrash::DeletedAt).to_owned())
+            .await
+    }
+}
+
+/// Set when a post is moved to the trash, and cleared when it's restored.
+#[derive(Iden)]
+pub enum PostTrash {
+    DeletedAt,
+}
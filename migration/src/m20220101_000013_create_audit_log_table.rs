@@ -0,0 +1,77 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AuditLog::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AuditLog::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    // entries outlive the users who made them
+                    .col(ColumnDef::new(AuditLog::ActorId).integer())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audit_log-actor_id")
+                            .from(AuditLog::Table, AuditLog::ActorId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::SetNull),
+                    )
+                    .col(ColumnDef::new(AuditLog::Action).string().not_null())
+                    .col(ColumnDef::new(AuditLog::TargetType).string().not_null())
+                    .col(ColumnDef::new(AuditLog::TargetId).integer().not_null())
+                    .col(ColumnDef::new(AuditLog::Before).json_binary())
+                    .col(ColumnDef::new(AuditLog::After).json_binary())
+                    .col(ColumnDef::new(AuditLog::Ip).string())
+                    .col(ColumnDef::new(AuditLog::UserAgent).text())
+                    .col(ColumnDef::new(AuditLog::CreatedAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audit_log-target")
+                    .table(AuditLog::Table)
+                    .col(AuditLog::TargetType)
+                    .col(AuditLog::TargetId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AuditLog::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub enum AuditLog {
+    Table,
+    Id,
+    ActorId,
+    Action,
+    TargetType,
+    TargetId,
+    Before,
+    After,
+    Ip,
+    UserAgent,
+    CreatedAt,
+}
@@ -0,0 +1,32 @@
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_user_table::User;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(User::Table)
+                    .add_column(ColumnDef::new(TotpReplay::TotpLastStep).big_integer())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(Table::alter().table(User::Table).drop_column(TotpReplay::TotpLastStep).to_owned())
+            .await
+    }
+}
+
+/// The time step of the last TOTP code accepted, so the same code can't be used twice.
+#[derive(Iden)]
+pub enum TotpReplay {
+    TotpLastStep,
+}
@@ -0,0 +1,47 @@
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Marks a credential as an API key rather than a JWT.
+pub const KEY_PREFIX: &str = "bk_";
+
+/// A freshly minted key. `key` is only ever shown to the user once.
+pub struct NewKey {
+    pub prefix: String,
+    pub key: String,
+    pub hash: String,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect()
+}
+
+/// Keys look like `bk_<8 char prefix>_<32 char secret>`; the prefix is stored in the
+/// clear for lookup and the whole key is stored hashed.
+pub fn generate() -> NewKey {
+    let prefix = random_string(8);
+    let key = format!("{}{}_{}", KEY_PREFIX, prefix, random_string(32));
+    let hash = hash(&key);
+
+    NewKey { prefix, key, hash }
+}
+
+/// Keys are long random strings, so a plain SHA-256 is enough to store them.
+pub fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// Returns the lookup prefix of a well-formed key.
+pub fn prefix(key: &str) -> Option<&str> {
+    let (prefix, secret) = key.strip_prefix(KEY_PREFIX)?.split_once('_')?;
+
+    if prefix.len() != 8 || secret.is_empty() {
+        return None;
+    }
+
+    Some(prefix)
+}
@@ -0,0 +1,80 @@
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use sea_orm::*;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::client_ip::client_ip;
+
+/// Who made a change and where their request came from.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    /// `None` for anonymous requests such as registration, and for scheduled jobs.
+    pub user_id: Option<i32>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Actor {
+    /// Whoever sent `req`, signed in as `user_id` if anyone.
+    pub fn new(req: &HttpRequest, user_id: Option<i32>) -> Self {
+        Actor {
+            user_id,
+            ip: client_ip(req).map(|ip| ip.to_string()),
+            user_agent: req.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()).map(String::from),
+        }
+    }
+
+    /// The server itself, for scheduled jobs.
+    pub fn system() -> Self {
+        Actor { user_id: None, ip: None, user_agent: None }
+    }
+
+    /// The same client, once it turned out to be signed in as `user_id`.
+    pub fn as_user(&self, user_id: i32) -> Self {
+        Actor { user_id: Some(user_id), ..self.clone() }
+    }
+}
+
+/// One change to record, with snapshots of its target before and after.
+pub struct Entry {
+    action: &'static str,
+    target_type: &'static str,
+    target_id: i32,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl Entry {
+    pub fn new(action: &'static str, target_type: &'static str, target_id: i32) -> Self {
+        Entry { action, target_type, target_id, before: None, after: None }
+    }
+
+    pub fn before(self, target: &impl Serialize) -> Self {
+        Entry { before: Some(serde_json::to_value(target).expect("could not serialize audit snapshot")), ..self }
+    }
+
+    pub fn after(self, target: &impl Serialize) -> Self {
+        Entry { after: Some(serde_json::to_value(target).expect("could not serialize audit snapshot")), ..self }
+    }
+}
+
+/// Writes `entry` to the audit log as done by `actor`.
+pub async fn record(conn: &impl ConnectionTrait, actor: &Actor, entry: Entry) {
+    entities::audit_log::ActiveModel {
+        actor_id: Set(actor.user_id),
+        action: Set(entry.action.to_string()),
+        target_type: Set(entry.target_type.to_string()),
+        target_id: Set(entry.target_id),
+        before: Set(entry.before),
+        after: Set(entry.after),
+        ip: Set(actor.ip.clone()),
+        user_agent: Set(actor.user_agent.clone()),
+        created_at: Set(Utc::now().into()),
+        ..Default::default()
+    }
+    .insert(conn)
+    .await
+    .expect("could not write audit log");
+}
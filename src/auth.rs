@@ -0,0 +1,235 @@
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use jsonwebtoken::errors::{ErrorKind, Result as JwtResult};
+use jsonwebtoken::{decode, decode_header, Header, Validation};
+use sea_orm::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::api_keys;
+use crate::keyring::keyring;
+
+pub const POSTS_WRITE: &str = "posts:write";
+pub const POSTS_DELETE: &str = "posts:delete";
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+
+/// Scopes an API key can be granted.
+pub const SCOPES: &[&str] = &[POSTS_WRITE, POSTS_DELETE, USERS_READ, USERS_WRITE];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub user_id: i32,
+    pub email: String,
+    pub exp: i64,
+    /// Set on the short-lived token handed out after a correct password when the
+    /// account still needs a second factor. Such tokens are only good for
+    /// `POST /users/login/2fa`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub two_factor_pending: bool,
+}
+
+/// Signs any claims with the current signing key.
+pub fn encode_token<T: Serialize>(claims: &T) -> JwtResult<String> {
+    let keyring = keyring();
+    let signing_key = keyring.signing_key().ok_or(ErrorKind::InvalidKeyFormat)?;
+
+    let mut header = Header::new(signing_key.algorithm);
+    header.kid = signing_key.kid.clone();
+
+    // `signing_key` only returns keys with a private half
+    jsonwebtoken::encode(&header, claims, signing_key.encoding_key().unwrap())
+}
+
+/// Verifies a token signed by `encode_token` and returns its claims.
+pub fn decode_token<T: DeserializeOwned>(token: &str) -> JwtResult<T> {
+    let header = decode_header(token)?;
+
+    // tokens signed by a key that has rotated out are rejected like bad signatures
+    let keyring = keyring();
+    let key = keyring.verification_key(header.kid.as_deref()).ok_or(ErrorKind::InvalidSignature)?;
+
+    let validation = Validation::new(key.algorithm);
+    let data = decode::<T>(token, key.decoding_key(), &validation)?;
+    Ok(data.claims)
+}
+
+pub fn create_jwt(user_id: i32, email: &str, days: i64) -> JwtResult<String> {
+    let expiration_date = Utc::now() + Duration::days(days);
+
+    encode_token(&Claims {
+        user_id,
+        email: email.to_string(),
+        exp: expiration_date.timestamp(),
+        two_factor_pending: false,
+    })
+}
+
+pub fn create_pending_jwt(user_id: i32, email: &str) -> JwtResult<String> {
+    let expiration_date = Utc::now() + Duration::minutes(5);
+
+    encode_token(&Claims {
+        user_id,
+        email: email.to_string(),
+        exp: expiration_date.timestamp(),
+        two_factor_pending: true,
+    })
+}
+
+/// Validates an access token, rejecting tokens still waiting on a second factor.
+pub fn validate_token(token: &str) -> JwtResult<Claims> {
+    let claims = decode_token::<Claims>(token)?;
+
+    if claims.two_factor_pending {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+
+    Ok(claims)
+}
+
+/// Validates a token returned by `login` for an account with two-factor enabled.
+pub fn validate_pending_token(token: &str) -> JwtResult<Claims> {
+    let claims = decode_token::<Claims>(token)?;
+
+    if !claims.two_factor_pending {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+
+    Ok(claims)
+}
+
+/// The caller behind a request.
+#[derive(Debug)]
+pub struct Identity {
+    pub user_id: i32,
+    /// Filled in from the user row once the credential checks out.
+    pub is_admin: bool,
+    /// Scopes of the API key used, `None` when authenticated with a JWT.
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Identity {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|s| s == scope),
+            None => true,
+        }
+    }
+}
+
+/// The raw credential, from `X-Api-Key` or a bearer `Authorization` header.
+pub fn credential(req: &HttpRequest) -> Option<&str> {
+    if let Some(key) = req.headers().get("X-Api-Key") {
+        return key.to_str().ok();
+    }
+
+    req.headers()
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// The unrevoked API key `key` belongs to, if it's genuine.
+pub async fn find_api_key(conn: &DatabaseConnection, key: &str) -> Option<entities::api_key::Model> {
+    let prefix = api_keys::prefix(key)?;
+
+    entities::api_key::Entity::find()
+        .filter(entities::api_key::Column::Prefix.eq(prefix))
+        .filter(entities::api_key::Column::RevokedAt.is_null())
+        .one(conn)
+        .await
+        .expect("could not find api key")
+        .filter(|api_key| api_key.key_hash == api_keys::hash(key))
+}
+
+/// Looks up an API key, recording when it was last used.
+async fn authenticate_api_key(conn: &DatabaseConnection, key: &str) -> Result<Identity, AuthError> {
+    let api_key = match find_api_key(conn, key).await {
+        Some(api_key) => api_key,
+        None => return Err(AuthError::Unauthorized("invalid api key".to_string())),
+    };
+
+    let identity = Identity {
+        user_id: api_key.user_id,
+        is_admin: false,
+        scopes: Some(api_key.scopes.split_whitespace().map(str::to_owned).collect()),
+    };
+
+    let mut api_key: entities::api_key::ActiveModel = api_key.into();
+    api_key.last_used_at = Set(Some(Utc::now().into()));
+    api_key.update(conn).await.expect("could not update api key");
+
+    Ok(identity)
+}
+
+/// Authenticates a request with either a JWT or an API key.
+///
+/// `scope` is what an API key must have been granted to use the route; JWTs carry
+/// every scope. Routes passing `None` can't be used with an API key at all.
+pub async fn authenticate(conn: &DatabaseConnection, req: &HttpRequest, needs_admin: bool, scope: Option<&str>) -> Result<Identity, HttpResponse> {
+    authenticate_credential(conn, credential(req), needs_admin, scope).await.map_err(HttpResponse::from)
+}
+
+/// Why a credential was turned away.
+#[derive(Debug)]
+pub enum AuthError {
+    /// Missing or invalid credential, or an inactive or non-admin user.
+    Unauthorized(String),
+    /// A valid API key without the needed scope.
+    Forbidden(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthorized(message) | AuthError::Forbidden(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<AuthError> for HttpResponse {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::Unauthorized(message) => HttpResponse::Unauthorized().body(message),
+            AuthError::Forbidden(message) => HttpResponse::Forbidden().body(message),
+        }
+    }
+}
+
+/// The checks behind `authenticate`, for callers that already pulled out the credential.
+pub async fn authenticate_credential(conn: &DatabaseConnection, credential: Option<&str>, needs_admin: bool, scope: Option<&str>) -> Result<Identity, AuthError> {
+    let credential = match credential {
+        Some(credential) => credential,
+        None => return Err(AuthError::Unauthorized("invalid token".to_string())),
+    };
+
+    let identity = if credential.starts_with(api_keys::KEY_PREFIX) {
+        authenticate_api_key(conn, credential).await?
+    } else {
+        match validate_token(credential) {
+            Ok(claims) => Identity { user_id: claims.user_id, is_admin: false, scopes: None },
+            Err(e) => return Err(AuthError::Unauthorized(format!("could not validate token: {}", e))),
+        }
+    };
+
+    match scope {
+        Some(scope) if !identity.has_scope(scope) => {
+            return Err(AuthError::Forbidden(format!("api key is missing the {} scope", scope)));
+        }
+        None if identity.scopes.is_some() => {
+            return Err(AuthError::Forbidden("api keys can't be used for this route".to_string()));
+        }
+        _ => (),
+    }
+
+    let user = entities::user::Entity::find_by_id(identity.user_id)
+        .one(conn)
+        .await
+        .expect("could not find user");
+
+    match user {
+        Some(user) if user.is_active && (user.is_admin || !needs_admin) => Ok(Identity { is_admin: user.is_admin, ..identity }),
+        _ => Err(AuthError::Unauthorized("You are unauthorized to use this route.".to_string())),
+    }
+}
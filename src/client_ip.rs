@@ -0,0 +1,112 @@
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::HttpRequest;
+
+/// Proxies whose `X-Forwarded-For` is believed, from the comma-separated `TRUSTED_PROXIES`.
+fn trusted_proxies() -> &'static [IpAddr] {
+    static TRUSTED_PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+    TRUSTED_PROXIES.get_or_init(|| {
+        std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| proxy.parse().expect("invalid address in TRUSTED_PROXIES"))
+            .collect()
+    })
+}
+
+/// The address a request came from.
+///
+/// That's the peer, unless the peer is a trusted proxy. Then it's the rightmost
+/// `X-Forwarded-For` address that isn't one, since anything further left was sent
+/// by the client and can be made up.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    Some(resolve(req.peer_addr()?.ip(), req.headers(), trusted_proxies()))
+}
+
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    let mut ip = peer;
+
+    if !trusted.contains(&ip) {
+        return ip;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    for hop in forwarded.iter().rev() {
+        ip = match hop.trim().parse() {
+            Ok(hop) => hop,
+            Err(_) => break,
+        };
+
+        if !trusted.contains(&ip) {
+            break;
+        }
+    }
+
+    ip
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+    const INNER_PROXY: &str = "10.0.0.2";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// The client address seen through a request from `peer` with these `X-Forwarded-For` headers.
+    fn client(peer: &str, forwarded: &[&str]) -> IpAddr {
+        let mut req = TestRequest::default();
+        for value in forwarded {
+            req = req.append_header(("X-Forwarded-For", *value));
+        }
+
+        resolve(ip(peer), req.to_http_request().headers(), &[ip(PROXY), ip(INNER_PROXY)])
+    }
+
+    #[test]
+    fn untrusted_peers_are_taken_as_is() {
+        assert_eq!(client("198.51.100.7", &["203.0.113.9"]), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn trusted_peers_without_the_header_are_the_client() {
+        assert_eq!(client(PROXY, &[]), ip(PROXY));
+    }
+
+    #[test]
+    fn spoofed_leading_hops_are_ignored() {
+        assert_eq!(client(PROXY, &["1.2.3.4, 203.0.113.9"]), ip("203.0.113.9"));
+        assert_eq!(client(PROXY, &["1.2.3.4", "203.0.113.9"]), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped() {
+        assert_eq!(client(PROXY, &["1.2.3.4, 203.0.113.9, 10.0.0.2"]), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn all_hops_trusted_gives_the_furthest_proxy() {
+        assert_eq!(client(PROXY, &["10.0.0.2, 10.0.0.1"]), ip(INNER_PROXY));
+    }
+
+    #[test]
+    fn malformed_hops_stop_the_walk() {
+        assert_eq!(client(PROXY, &["203.0.113.9, not-an-ip"]), ip(PROXY));
+        assert_eq!(client(PROXY, &["not-an-ip, 203.0.113.9"]), ip("203.0.113.9"));
+        assert_eq!(client(PROXY, &["203.0.113.9, 10.0.0.2, garbage"]), ip(PROXY));
+    }
+}
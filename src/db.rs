@@ -0,0 +1,25 @@
+use sea_orm::{DbErr, RuntimeErr};
+
+/// Postgres SQLSTATE for `unique_violation`.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Whether a failed statement was rejected by a unique constraint or index.
+pub fn is_unique_violation(err: &DbErr) -> bool {
+    unique_violation(err).is_some()
+}
+
+/// Whether a failed statement was rejected by the unique constraint or index `name`.
+pub fn violates_unique(err: &DbErr, name: &str) -> bool {
+    unique_violation(err).flatten().as_deref() == Some(name)
+}
+
+/// For a unique violation, the constraint or index it hit, if the database said.
+fn unique_violation(err: &DbErr) -> Option<Option<String>> {
+    match err {
+        DbErr::Exec(RuntimeErr::SqlxError(e)) | DbErr::Query(RuntimeErr::SqlxError(e)) => e
+            .as_database_error()
+            .filter(|e| e.code().is_some_and(|code| code == UNIQUE_VIOLATION))
+            .map(|e| e.constraint().map(str::to_owned)),
+        _ => None,
+    }
+}
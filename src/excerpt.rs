@@ -0,0 +1,128 @@
+use pulldown_cmark::{Event, Parser, Tag};
+use sea_orm::*;
+
+use entities::post::Entity as Post;
+
+/// Length generated excerpts are cut down to.
+pub const MAX_EXCERPT_CHARS: usize = 200;
+
+/// Average adult reading speed used for reading time estimates.
+const WORDS_PER_MINUTE: usize = 200;
+
+/// Plain text of each top-level paragraph, without markup, images or raw HTML.
+fn paragraphs(markdown: &str) -> Vec<String> {
+    let mut paragraphs = Vec::new();
+    let mut current: Option<String> = None;
+    let mut in_image = false;
+
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Paragraph) => current = Some(String::new()),
+            Event::End(Tag::Paragraph) => paragraphs.extend(current.take()),
+            Event::Start(Tag::Image(..)) => in_image = true,
+            Event::End(Tag::Image(..)) => in_image = false,
+            Event::Text(text) | Event::Code(text) if !in_image => {
+                if let Some(current) = current.as_mut() {
+                    current.push_str(&text);
+                }
+            }
+            Event::SoftBreak | Event::HardBreak => {
+                if let Some(current) = current.as_mut() {
+                    current.push(' ');
+                }
+            }
+            _ => (),
+        }
+    }
+
+    paragraphs
+}
+
+/// Words of readable text, counting code and headings but not markup.
+pub fn word_count(markdown: &str) -> usize {
+    Parser::new(markdown)
+        .map(|event| match event {
+            Event::Text(text) | Event::Code(text) => text.split_whitespace().count(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Whole minutes to read `words`, at least one.
+pub fn reading_time(words: usize) -> usize {
+    words.div_ceil(WORDS_PER_MINUTE).max(1)
+}
+
+/// The first paragraph with any text in it, stripped of markup and cut at a word
+/// boundary to at most `MAX_EXCERPT_CHARS`.
+pub fn generate(markdown: &str) -> String {
+    let paragraph = paragraphs(markdown)
+        .into_iter()
+        .map(|paragraph| paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+        .find(|paragraph| !paragraph.is_empty())
+        .unwrap_or_default();
+
+    if paragraph.chars().count() <= MAX_EXCERPT_CHARS {
+        return paragraph;
+    }
+
+    // leave room for the ellipsis
+    let mut excerpt = String::new();
+    for word in paragraph.split(' ') {
+        if excerpt.chars().count() + word.chars().count() + 1 > MAX_EXCERPT_CHARS - 1 {
+            break;
+        }
+        if !excerpt.is_empty() {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(word);
+    }
+
+    // a single huge word still gets cut rather than dropped
+    if excerpt.is_empty() {
+        excerpt = paragraph.chars().take(MAX_EXCERPT_CHARS - 1).collect();
+    }
+
+    excerpt.trim_end_matches(|c: char| c.is_ascii_punctuation()).to_string() + "…"
+}
+
+/// Excerpt, word count and reading time to store with a post. An author-supplied
+/// excerpt wins over the generated one.
+pub fn summarize(text: &str, custom_excerpt: Option<&str>) -> (String, i32, i32) {
+    let excerpt = match custom_excerpt.map(str::trim).filter(|excerpt| !excerpt.is_empty()) {
+        Some(excerpt) => excerpt.to_string(),
+        None => generate(text),
+    };
+    let words = word_count(text);
+
+    (excerpt, words as i32, reading_time(words) as i32)
+}
+
+/// Fills in posts written before excerpts existed.
+pub async fn backfill(conn: &DatabaseConnection) -> Result<(), DbErr> {
+    loop {
+        let posts = Post::find()
+            .filter(entities::post::Column::Excerpt.is_null())
+            .limit(100)
+            .all(conn)
+            .await?;
+
+        if posts.is_empty() {
+            return Ok(());
+        }
+
+        for post in posts {
+            let (excerpt, word_count, reading_time) = summarize(&post.text, None);
+
+            entities::post::ActiveModel {
+                id: Set(post.id),
+                excerpt: Set(Some(excerpt)),
+                word_count: Set(word_count),
+                reading_time: Set(reading_time),
+                ..Default::default()
+            }
+            .update(conn)
+            .await?;
+        }
+    }
+}
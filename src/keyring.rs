@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use simple_asn1::ASN1Block;
+
+/// One entry of the `JWT_KEYS_FILE` manifest. Key paths are relative to the manifest.
+#[derive(Debug, Deserialize)]
+struct ManifestEntry {
+    kid: String,
+    /// `RS256` or `EdDSA`.
+    alg: String,
+    /// Only needed while the key is (or will be) used for signing.
+    private_key: Option<PathBuf>,
+    public_key: PathBuf,
+    /// When the key starts signing tokens. Until then it is only published.
+    not_before: DateTime<Utc>,
+}
+
+pub struct SigningKey {
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+    /// Public JWK, `None` for the shared secret which must never be published.
+    jwk: Option<Value>,
+    not_before: DateTime<Utc>,
+    /// Set once a newer key takes over: the old one keeps verifying until then.
+    retire_at: Option<DateTime<Utc>>,
+}
+
+impl SigningKey {
+    pub fn encoding_key(&self) -> Option<&EncodingKey> {
+        self.encoding.as_ref()
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    fn is_retired(&self, now: DateTime<Utc>) -> bool {
+        self.retire_at.is_some_and(|retire_at| retire_at <= now)
+    }
+}
+
+/// The set of keys tokens are signed and verified with.
+///
+/// Without `JWT_KEYS_FILE` this is a single HS256 key from `JWT_SECRET`, as before.
+/// With it, every manifest key is published in the JWKS from the moment it is listed;
+/// the newest one whose `not_before` has passed signs new tokens, and each older key
+/// keeps verifying for `JWT_ROTATION_GRACE_SECS` after the next key with a private half
+/// took over.
+pub struct KeyRing {
+    keys: Vec<SigningKey>,
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Pulls the modulus and exponent out of an RSA public key, either PKCS#1 or SPKI.
+fn rsa_components(der: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let blocks = simple_asn1::from_der(der).map_err(|e| e.to_string())?;
+
+    match blocks.first() {
+        Some(ASN1Block::Sequence(_, items)) => match items.as_slice() {
+            [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => Ok((n.to_bytes_be().1, e.to_bytes_be().1)),
+            [ASN1Block::Sequence(_, _), ASN1Block::BitString(_, _, key)] => rsa_components(key),
+            _ => Err("unexpected rsa public key structure".to_string()),
+        },
+        _ => Err("unexpected rsa public key structure".to_string()),
+    }
+}
+
+impl KeyRing {
+    pub fn from_env() -> Result<KeyRing, String> {
+        match std::env::var("JWT_KEYS_FILE") {
+            Ok(path) => KeyRing::from_manifest(Path::new(&path)),
+            Err(_) => {
+                let secret = std::env::var("JWT_SECRET").unwrap_or("secret".to_string());
+
+                Ok(KeyRing {
+                    keys: vec![SigningKey {
+                        kid: None,
+                        algorithm: Algorithm::HS256,
+                        encoding: Some(EncodingKey::from_secret(secret.as_ref())),
+                        decoding: DecodingKey::from_secret(secret.as_ref()),
+                        jwk: None,
+                        not_before: DateTime::<Utc>::MIN_UTC,
+                        retire_at: None,
+                    }],
+                })
+            }
+        }
+    }
+
+    fn from_manifest(path: &Path) -> Result<KeyRing, String> {
+        let manifest = std::fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let mut entries: Vec<ManifestEntry> = serde_json::from_slice(&manifest).map_err(|e| format!("invalid key manifest: {}", e))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        if entries.is_empty() {
+            return Err("key manifest lists no keys".to_string());
+        }
+
+        entries.sort_by_key(|entry| entry.not_before);
+
+        let grace = Duration::seconds(
+            std::env::var("JWT_ROTATION_GRACE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(2 * 24 * 60 * 60),
+        );
+        // a key only takes over signing if it has a private half, so verify-only
+        // entries listed after a key don't start its grace period
+        let successors: Vec<Option<DateTime<Utc>>> = (0..entries.len())
+            .map(|i| entries[i + 1..].iter().find(|entry| entry.private_key.is_some()).map(|entry| entry.not_before))
+            .collect();
+
+        let mut keys = Vec::new();
+
+        for (entry, successor) in entries.into_iter().zip(successors) {
+            let read = |file: &Path| {
+                std::fs::read(dir.join(file)).map_err(|e| format!("could not read key {}: {}", file.display(), e))
+            };
+            let invalid = |e: jsonwebtoken::errors::Error| format!("invalid key {}: {}", entry.kid, e);
+
+            let public = read(&entry.public_key)?;
+            let private = entry.private_key.as_deref().map(read).transpose()?;
+            let public_der = pem::parse(&public).map_err(|e| format!("invalid key {}: {}", entry.kid, e))?.contents;
+
+            let (algorithm, encoding, decoding, jwk) = match entry.alg.as_str() {
+                "RS256" => {
+                    let (n, e) = rsa_components(&public_der)?;
+                    (
+                        Algorithm::RS256,
+                        private.map(|key| EncodingKey::from_rsa_pem(&key)).transpose().map_err(invalid)?,
+                        DecodingKey::from_rsa_pem(&public).map_err(invalid)?,
+                        json!({ "kty": "RSA", "n": base64url(&n), "e": base64url(&e) }),
+                    )
+                }
+                "EdDSA" => {
+                    // the raw Ed25519 key is the last 32 bytes of the SPKI structure
+                    let x = &public_der[public_der.len().saturating_sub(32)..];
+                    (
+                        Algorithm::EdDSA,
+                        private.map(|key| EncodingKey::from_ed_pem(&key)).transpose().map_err(invalid)?,
+                        DecodingKey::from_ed_pem(&public).map_err(invalid)?,
+                        json!({ "kty": "OKP", "crv": "Ed25519", "x": base64url(x) }),
+                    )
+                }
+                other => return Err(format!("unsupported algorithm {} for key {}", other, entry.kid)),
+            };
+
+            let mut jwk = jwk;
+            jwk["kid"] = json!(entry.kid);
+            jwk["alg"] = json!(entry.alg);
+            jwk["use"] = json!("sig");
+
+            keys.push(SigningKey {
+                kid: Some(entry.kid),
+                algorithm,
+                encoding,
+                decoding,
+                jwk: Some(jwk),
+                not_before: entry.not_before,
+                retire_at: successor.map(|not_before| not_before + grace),
+            });
+        }
+
+        Ok(KeyRing { keys })
+    }
+
+    /// The newest key that is active and has a private half.
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        let now = Utc::now();
+
+        self.keys
+            .iter()
+            .filter(|key| key.not_before <= now && key.encoding.is_some())
+            .max_by_key(|key| key.not_before)
+    }
+
+    /// The key a token's `kid` refers to, if it's still accepted.
+    pub fn verification_key(&self, kid: Option<&str>) -> Option<&SigningKey> {
+        let now = Utc::now();
+
+        self.keys
+            .iter()
+            .find(|key| key.kid.as_deref() == kid && !key.is_retired(now))
+    }
+
+    /// Public keys for `/.well-known/jwks.json`, including ones not signing yet.
+    pub fn jwks(&self) -> Value {
+        let now = Utc::now();
+        let keys: Vec<&Value> = self
+            .keys
+            .iter()
+            .filter(|key| !key.is_retired(now))
+            .filter_map(|key| key.jwk.as_ref())
+            .collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+static KEYRING: OnceLock<RwLock<Arc<KeyRing>>> = OnceLock::new();
+
+fn slot() -> &'static RwLock<Arc<KeyRing>> {
+    KEYRING.get_or_init(|| RwLock::new(Arc::new(KeyRing::from_env().expect("could not load jwt keys"))))
+}
+
+/// The currently loaded key ring.
+pub fn keyring() -> Arc<KeyRing> {
+    slot().read().unwrap().clone()
+}
+
+/// Re-reads the key configuration, keeping the old keys if the new ones don't load.
+pub fn reload() -> Result<(), String> {
+    let keyring = KeyRing::from_env()?;
+    *slot().write().unwrap() = Arc::new(keyring);
+    Ok(())
+}
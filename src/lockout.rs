@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::{http::header, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::*;
+use sea_orm::sea_query::{Expr, OnConflict};
+
+use entities::login_attempt::Entity as LoginAttempt;
+
+/// Failed-login bookkeeping for a single account or IP address.
+#[derive(Debug, Clone)]
+pub struct AttemptRecord {
+    failures: i32,
+    last_failure: DateTime<Utc>,
+    blocked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LockoutConfig {
+    /// Failures before an account is locked out.
+    pub max_account_attempts: i32,
+    /// Failures before an IP address is locked out, higher since addresses can be shared.
+    pub max_ip_attempts: i32,
+    /// Delay after the second failure, doubled for each one after that.
+    pub backoff_base: Duration,
+    /// How long a lockout lasts, also the window after which old failures are forgotten.
+    pub lockout: Duration,
+}
+
+impl LockoutConfig {
+    pub fn from_env() -> Self {
+        fn var(name: &str, default: i64) -> i64 {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        }
+
+        LockoutConfig {
+            max_account_attempts: var("LOGIN_MAX_ATTEMPTS", 5) as i32,
+            max_ip_attempts: var("LOGIN_MAX_ATTEMPTS_PER_IP", 20) as i32,
+            backoff_base: Duration::seconds(var("LOGIN_BACKOFF_BASE_SECS", 1)),
+            lockout: Duration::seconds(var("LOGIN_LOCKOUT_SECS", 900)),
+        }
+    }
+}
+
+/// Where failed attempts are kept. The in-memory store is per process, so use the
+/// database when running more than one instance.
+pub enum AttemptStore {
+    Memory(Mutex<HashMap<String, AttemptRecord>>),
+    Database(DatabaseConnection),
+}
+
+impl AttemptStore {
+    async fn get(&self, key: &str) -> Result<Option<AttemptRecord>, DbErr> {
+        match self {
+            AttemptStore::Memory(map) => Ok(map.lock().unwrap().get(key).cloned()),
+            AttemptStore::Database(conn) => Ok(LoginAttempt::find_by_id(key.to_owned())
+                .one(conn)
+                .await?
+                .map(|attempt| AttemptRecord {
+                    failures: attempt.failures,
+                    last_failure: attempt.last_failure.with_timezone(&Utc),
+                    blocked_until: attempt.blocked_until.map(|t| t.with_timezone(&Utc)),
+                })),
+        }
+    }
+
+    /// Counts a failure against `key` in one atomic step, so parallel attempts can't
+    /// lose increments. Failures before `window_start` are forgotten, and `block`
+    /// decides how long the key is blocked given the new count, which is returned.
+    async fn record(
+        &self,
+        key: &str,
+        now: DateTime<Utc>,
+        window_start: DateTime<Utc>,
+        block: impl Fn(i32) -> Option<DateTime<Utc>>,
+    ) -> Result<i32, DbErr> {
+        match self {
+            AttemptStore::Memory(map) => {
+                let mut map = map.lock().unwrap();
+                let failures = match map.get(key) {
+                    Some(record) if record.last_failure >= window_start => record.failures + 1,
+                    _ => 1,
+                };
+
+                map.insert(key.to_owned(), AttemptRecord { failures, last_failure: now, blocked_until: block(failures) });
+                Ok(failures)
+            }
+            AttemptStore::Database(conn) => {
+                let attempt = entities::login_attempt::ActiveModel {
+                    key: Set(key.to_owned()),
+                    failures: Set(1),
+                    last_failure: Set(now.into()),
+                    blocked_until: Set(None),
+                };
+
+                let last_failure = Expr::tbl(LoginAttempt, entities::login_attempt::Column::LastFailure);
+                let failures = Expr::tbl(LoginAttempt, entities::login_attempt::Column::Failures);
+
+                let attempt = LoginAttempt::insert(attempt)
+                    .on_conflict(
+                        OnConflict::column(entities::login_attempt::Column::Key)
+                            .values([
+                                (
+                                    entities::login_attempt::Column::Failures,
+                                    Expr::case(last_failure.gte(window_start), failures.add(1)).finally(1).into(),
+                                ),
+                                (entities::login_attempt::Column::LastFailure, Expr::value(now)),
+                            ])
+                            .to_owned(),
+                    )
+                    .exec_with_returning(conn)
+                    .await?;
+
+                // only lands if no later failure has bumped the count in the meantime,
+                // so a shorter block can't overwrite a longer one
+                LoginAttempt::update_many()
+                    .col_expr(entities::login_attempt::Column::BlockedUntil, Expr::value(block(attempt.failures)))
+                    .filter(entities::login_attempt::Column::Key.eq(key))
+                    .filter(entities::login_attempt::Column::Failures.eq(attempt.failures))
+                    .exec(conn)
+                    .await?;
+
+                Ok(attempt.failures)
+            }
+        }
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), DbErr> {
+        match self {
+            AttemptStore::Memory(map) => {
+                map.lock().unwrap().remove(key);
+            }
+            AttemptStore::Database(conn) => {
+                LoginAttempt::delete_by_id(key.to_owned()).exec(conn).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> Result<(), DbErr> {
+        match self {
+            AttemptStore::Memory(map) => {
+                map.lock().unwrap().retain(|_, record| record.last_failure >= before);
+            }
+            AttemptStore::Database(conn) => {
+                LoginAttempt::delete_many()
+                    .filter(entities::login_attempt::Column::LastFailure.lt(before))
+                    .exec(conn)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Tracks failed logins per account and per IP address, applying exponential
+/// backoff and a temporary lockout once a threshold is reached.
+pub struct LoginGuard {
+    config: LockoutConfig,
+    store: AttemptStore,
+}
+
+impl LoginGuard {
+    pub fn new(config: LockoutConfig, store: AttemptStore) -> Self {
+        LoginGuard { config, store }
+    }
+
+    /// Builds the guard from `LOGIN_*` env vars, with `LOGIN_ATTEMPT_STORE=database`
+    /// selecting the database backend over the default in-memory one.
+    pub fn from_env(conn: DatabaseConnection) -> Self {
+        let store = match std::env::var("LOGIN_ATTEMPT_STORE").as_deref() {
+            Ok("database") => AttemptStore::Database(conn),
+            _ => AttemptStore::Memory(Mutex::new(HashMap::new())),
+        };
+
+        LoginGuard::new(LockoutConfig::from_env(), store)
+    }
+
+    pub fn account_key(user_id: i32) -> String {
+        format!("account:id:{}", user_id)
+    }
+
+    /// Key for a login that matches no account. It has its own namespace, so failing
+    /// as `"7"` can't lock out the user with id 7.
+    pub fn unknown_login_key(login: &str) -> String {
+        format!("account:login:{}", login.trim().to_lowercase())
+    }
+
+    pub fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+
+    /// Returns how long the caller has to wait if `key` is currently blocked.
+    pub async fn retry_after(&self, key: &str) -> Result<Option<Duration>, DbErr> {
+        let now = Utc::now();
+
+        Ok(self
+            .store
+            .get(key)
+            .await?
+            .and_then(|record| record.blocked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now))
+    }
+
+    /// When a key with `failures` failures in a row is blocked until, where
+    /// `max_attempts` is the lockout threshold.
+    fn blocked_until(&self, failures: i32, max_attempts: i32, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if failures >= max_attempts {
+            Some(now + self.config.lockout)
+        } else if failures > 1 {
+            let delay = self.config.backoff_base * 2i32.saturating_pow(failures as u32 - 2);
+            Some(now + delay.min(self.config.lockout))
+        } else {
+            None
+        }
+    }
+
+    /// Counts a failed attempt against `key`, where `max_attempts` is the lockout threshold.
+    async fn record_failure(&self, key: &str, max_attempts: i32) -> Result<(), DbErr> {
+        let now = Utc::now();
+
+        // failures older than the lockout window, including a served lockout, no longer count
+        let window_start = now - self.config.lockout;
+        let failures = self
+            .store
+            .record(key, now, window_start, |failures| self.blocked_until(failures, max_attempts, now))
+            .await?;
+
+        if failures == 1 {
+            self.store.prune(window_start).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn record_account_failure(&self, key: &str) -> Result<(), DbErr> {
+        self.record_failure(key, self.config.max_account_attempts).await
+    }
+
+    pub async fn record_ip_failure(&self, key: &str) -> Result<(), DbErr> {
+        self.record_failure(key, self.config.max_ip_attempts).await
+    }
+
+    /// Forgets all failures for `key`, used on successful login and by admins.
+    pub async fn clear(&self, key: &str) -> Result<(), DbErr> {
+        self.store.remove(key).await
+    }
+}
+
+/// `429 Too Many Requests` telling the client when it may try again.
+pub fn too_many_attempts(wait: Duration) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, (wait.num_seconds() + 1).to_string()))
+        .body("too many failed login attempts, try again later")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::join_all;
+
+    use super::*;
+
+    fn config() -> LockoutConfig {
+        LockoutConfig {
+            max_account_attempts: 5,
+            max_ip_attempts: 20,
+            backoff_base: Duration::seconds(1),
+            lockout: Duration::seconds(900),
+        }
+    }
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(config(), AttemptStore::Memory(Mutex::new(HashMap::new())))
+    }
+
+    #[test]
+    fn backoff_doubles_until_the_lockout() {
+        let guard = guard();
+        let now = Utc::now();
+        let delay = |failures| guard.blocked_until(failures, 5, now).map(|until| until - now);
+
+        assert_eq!(delay(1), None);
+        assert_eq!(delay(2), Some(Duration::seconds(1)));
+        assert_eq!(delay(3), Some(Duration::seconds(2)));
+        assert_eq!(delay(4), Some(Duration::seconds(4)));
+        assert_eq!(delay(5), Some(Duration::seconds(900)));
+        assert_eq!(delay(6), Some(Duration::seconds(900)));
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_lockout() {
+        let guard = guard();
+        let now = Utc::now();
+
+        assert_eq!(guard.blocked_until(15, 20, now), Some(now + Duration::seconds(900)));
+        assert_eq!(guard.blocked_until(40, 100, now), Some(now + Duration::seconds(900)));
+    }
+
+    #[test]
+    fn unknown_logins_dont_share_keys_with_accounts() {
+        assert_ne!(LoginGuard::account_key(7), LoginGuard::unknown_login_key("7"));
+        assert_eq!(LoginGuard::unknown_login_key(" Alice "), LoginGuard::unknown_login_key("alice"));
+    }
+
+    #[actix_web::test]
+    async fn locks_out_at_the_threshold() {
+        let guard = guard();
+        let key = LoginGuard::account_key(1);
+
+        for _ in 0..4 {
+            guard.record_account_failure(&key).await.unwrap();
+        }
+        let wait = guard.retry_after(&key).await.unwrap().unwrap();
+        assert!(wait <= Duration::seconds(4), "blocked for {} before the threshold", wait);
+
+        guard.record_account_failure(&key).await.unwrap();
+        let wait = guard.retry_after(&key).await.unwrap().unwrap();
+        assert!(wait > Duration::seconds(800), "blocked for only {} at the threshold", wait);
+
+        guard.clear(&key).await.unwrap();
+        assert_eq!(guard.retry_after(&key).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn old_failures_are_forgotten() {
+        let guard = guard();
+        let now = Utc::now();
+        let window_start = now - config().lockout;
+
+        let stale = AttemptRecord { failures: 4, last_failure: window_start - Duration::seconds(1), blocked_until: None };
+        if let AttemptStore::Memory(map) = &guard.store {
+            map.lock().unwrap().insert("k".to_string(), stale);
+        }
+
+        let failures = guard.store.record("k", now, window_start, |_| None).await.unwrap();
+        assert_eq!(failures, 1);
+    }
+
+    #[actix_web::test]
+    async fn parallel_failures_all_count() {
+        let guard = guard();
+        let key = LoginGuard::ip_key("203.0.113.9");
+
+        for result in join_all((0..50).map(|_| guard.record_ip_failure(&key))).await {
+            result.unwrap();
+        }
+
+        let now = Utc::now();
+        let failures = guard.store.record(&key, now, now - config().lockout, |_| None).await.unwrap();
+        assert_eq!(failures, 51);
+    }
+}
@@ -1,7 +1,25 @@
 use actix_web::{web, App, HttpServer};
 
+mod api_keys;
+mod audit;
+mod auth;
+mod client_ip;
+mod db;
+mod excerpt;
+mod keyring;
+mod lockout;
+mod oidc;
+mod passwords;
+mod payload;
+mod rate_limit;
 mod routes;
+mod trash;
+mod two_factor;
+mod validation;
 use routes::init_routes;
+use lockout::LoginGuard;
+use oidc::Oidc;
+use rate_limit::{Quota, RateLimit, RateLimitStore};
 
 use migration::{Migrator, MigratorTrait};
 use sea_orm::Database;
@@ -17,10 +35,57 @@ async fn main() -> std::io::Result<()> {
     let db = Database::connect(std::env::var("DATABASE_URL").unwrap()).await.unwrap();
 
     Migrator::up(&db, None).await.unwrap();
+    excerpt::backfill(&db).await.expect("could not fill in post excerpts");
+
+    // shared across workers so the in-memory store sees every attempt
+    let login_guard = web::Data::new(LoginGuard::from_env(db.clone()));
+    let rate_limit_store = web::Data::new(RateLimitStore::from_env().await);
+    let oidc = Oidc::from_env().await.map(web::Data::new);
+    let graphql_schema = web::Data::new(routes::build_schema(db.clone()));
+
+    // fail at startup on a broken key manifest, then pick up rotated keys as they're added
+    keyring::keyring();
+    let reload_secs = std::env::var("JWT_KEYS_RELOAD_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(300);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(reload_secs));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = keyring::reload() {
+                tracing::error!("could not reload jwt keys: {}", e);
+            }
+        }
+    });
+
+    // trashed posts can be restored until they've been there for TRASH_RETENTION_DAYS
+    let purge_db = db.clone();
+    let retention = trash::retention();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(trash::PURGE_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            match trash::purge(&purge_db, retention).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("purged {} posts from the trash", purged),
+                Err(e) => tracing::error!("could not purge trashed posts: {}", e),
+            }
+        }
+    });
 
     HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
             .app_data(web::Data::new(db.clone()))
+            .app_data(login_guard.clone())
+            .app_data(rate_limit_store.clone())
+            .app_data(graphql_schema.clone());
+
+        // single sign-on routes answer 404 unless a provider is configured
+        if let Some(oidc) = &oidc {
+            app = app.app_data(oidc.clone());
+        }
+
+        app
+            .wrap(RateLimit::new("global", Quota::per_minute(600)))
             .configure(init_routes)
     })
         .bind(("0.0.0.0", 8080))?
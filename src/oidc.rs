@@ -0,0 +1,269 @@
+use std::sync::RwLock;
+
+use chrono::{Duration, Utc};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The parts of the provider's discovery document we use.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// What the callback needs to finish a login, kept in a signed cookie between the
+/// redirect to the provider and the callback.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub exp: i64,
+}
+
+/// Claims of a verified ID token.
+#[derive(Debug, Deserialize)]
+pub struct IdClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+/// When a login with an unknown identity gets an account.
+#[derive(Debug)]
+pub struct Provisioning {
+    /// Create a user for identities that don't match an account. `OIDC_AUTO_PROVISION`.
+    pub auto_provision: bool,
+    /// Email domains allowed to be provisioned, any if empty. `OIDC_ALLOWED_DOMAINS`.
+    pub allowed_domains: Vec<String>,
+    /// Link identities to an existing user with the same verified email. `OIDC_LINK_BY_EMAIL`.
+    pub link_by_email: bool,
+    /// Whether provisioned users start out active. `OIDC_PROVISION_ACTIVE`.
+    pub activate: bool,
+}
+
+impl Provisioning {
+    fn from_env() -> Self {
+        let flag = |name: &str, default: bool| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+
+        Provisioning {
+            auto_provision: flag("OIDC_AUTO_PROVISION", false),
+            allowed_domains: std::env::var("OIDC_ALLOWED_DOMAINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|domain| domain.trim().to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+            link_by_email: flag("OIDC_LINK_BY_EMAIL", false),
+            activate: flag("OIDC_PROVISION_ACTIVE", true),
+        }
+    }
+
+    pub fn allows_email(&self, email: &str) -> bool {
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase());
+
+        self.allowed_domains.is_empty() || domain.is_some_and(|domain| self.allowed_domains.contains(&domain))
+    }
+}
+
+/// An OpenID Connect provider used for single sign-on with the authorization code
+/// flow and PKCE.
+pub struct Oidc {
+    pub issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    scopes: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    /// Refreshed when a token names a key we haven't seen, so provider rotations just work.
+    jwks: RwLock<JwkSet>,
+    http: reqwest::Client,
+    pub provisioning: Provisioning,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Algorithms an ID token may use with a given provider key. Symmetric keys are
+/// never accepted from a JWKS.
+fn algorithms_for(params: &AlgorithmParameters) -> Vec<Algorithm> {
+    match params {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        AlgorithmParameters::OctetKey(_) => vec![],
+    }
+}
+
+impl Oidc {
+    /// Discovers the provider at `OIDC_ISSUER`, or returns `None` when single sign-on isn't configured.
+    pub async fn from_env() -> Option<Self> {
+        let issuer = std::env::var("OIDC_ISSUER").ok()?;
+        let client_id = std::env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set with OIDC_ISSUER");
+        let redirect_uri = std::env::var("OIDC_REDIRECT_URI").expect("OIDC_REDIRECT_URI must be set with OIDC_ISSUER");
+
+        let http = reqwest::Client::new();
+        let discovery_url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+
+        let discovery: Discovery = http
+            .get(discovery_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .expect("could not reach oidc provider")
+            .json()
+            .await
+            .expect("invalid oidc discovery document");
+
+        let oidc = Oidc {
+            issuer: discovery.issuer,
+            client_id,
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri,
+            scopes: std::env::var("OIDC_SCOPES").unwrap_or("openid email profile".to_string()),
+            authorization_endpoint: discovery.authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            jwks_uri: discovery.jwks_uri,
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+            http,
+            provisioning: Provisioning::from_env(),
+        };
+
+        oidc.refresh_jwks().await.expect("could not fetch oidc provider keys");
+
+        Some(oidc)
+    }
+
+    /// Whether the login cookie should be `Secure`.
+    pub fn is_https(&self) -> bool {
+        self.redirect_uri.starts_with("https://")
+    }
+
+    /// Starts a login: the URL to send the browser to and the state to check the callback against.
+    pub fn begin(&self) -> (String, PendingLogin) {
+        let pending = PendingLogin {
+            state: random_string(32),
+            nonce: random_string(32),
+            code_verifier: random_string(64),
+            exp: (Utc::now() + Duration::minutes(10)).timestamp(),
+        };
+
+        let code_challenge = base64::encode_config(Sha256::digest(pending.code_verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+        let query = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", &self.client_id),
+            ("redirect_uri", &self.redirect_uri),
+            ("scope", &self.scopes),
+            ("state", &pending.state),
+            ("nonce", &pending.nonce),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+        ])
+        .unwrap();
+
+        let separator = if self.authorization_endpoint.contains('?') { '&' } else { '?' };
+
+        (format!("{}{}{}", self.authorization_endpoint, separator, query), pending)
+    }
+
+    /// Exchanges the authorization code for an ID token and verifies it.
+    pub async fn finish(&self, code: &str, pending: &PendingLogin) -> Result<IdClaims, String> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_uri),
+            ("client_id", &self.client_id),
+            ("code_verifier", &pending.code_verifier),
+        ];
+
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let response = self
+            .http
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| format!("could not reach token endpoint: {}", e))?;
+
+        if !response.status().is_success() {
+            return Err(format!("token endpoint returned {}", response.status()));
+        }
+
+        let tokens: TokenResponse = response.json().await.map_err(|e| format!("invalid token response: {}", e))?;
+
+        self.verify_id_token(&tokens.id_token, &pending.nonce).await
+    }
+
+    async fn refresh_jwks(&self) -> Result<(), reqwest::Error> {
+        let jwks: JwkSet = self.http.get(&self.jwks_uri).send().await?.error_for_status()?.json().await?;
+        *self.jwks.write().unwrap() = jwks;
+        Ok(())
+    }
+
+    fn decoding_key(&self, kid: &str) -> Option<(DecodingKey, Vec<Algorithm>)> {
+        let jwks = self.jwks.read().unwrap();
+        let jwk = jwks.find(kid)?;
+
+        Some((DecodingKey::from_jwk(jwk).ok()?, algorithms_for(&jwk.algorithm)))
+    }
+
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<IdClaims, String> {
+        let header = decode_header(id_token).map_err(|e| format!("invalid id token: {}", e))?;
+        let kid = header.kid.ok_or("id token has no key id")?;
+
+        let (key, algorithms) = match self.decoding_key(&kid) {
+            Some(key) => key,
+            None => {
+                self.refresh_jwks().await.map_err(|e| format!("could not fetch provider keys: {}", e))?;
+                self.decoding_key(&kid).ok_or("id token signed with an unknown key")?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+
+        let claims = decode::<IdClaims>(id_token, &key, &validation)
+            .map_err(|e| format!("invalid id token: {}", e))?
+            .claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err("id token nonce mismatch".to_string());
+        }
+
+        Ok(claims)
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::OnceLock;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+/// Argon2id parameters for new hashes, read from `ARGON2_MEMORY_KIB`, `ARGON2_ITERATIONS`
+/// and `ARGON2_PARALLELISM`. The defaults follow the OWASP recommendation.
+fn params() -> &'static Params {
+    static PARAMS: OnceLock<Params> = OnceLock::new();
+
+    PARAMS.get_or_init(|| {
+        let var = |name: &str, default: u32| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+
+        Params::new(
+            var("ARGON2_MEMORY_KIB", 19 * 1024),
+            var("ARGON2_ITERATIONS", 2),
+            var("ARGON2_PARALLELISM", 1),
+            None,
+        )
+        .expect("invalid argon2 parameters")
+    })
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params().clone())
+}
+
+/// Legacy hashes from before the switch to Argon2id.
+fn is_bcrypt(hashed_password: &str) -> bool {
+    hashed_password.starts_with("$2")
+}
+
+/// Hashes a password with Argon2id in PHC string format.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Checks a password against either an Argon2 PHC string or a bcrypt hash. Anything
+/// else, such as the placeholder of accounts without a password, never matches.
+pub fn check_password(password: &str, hashed_password: &str) -> bool {
+    if is_bcrypt(hashed_password) {
+        return bcrypt::verify(password, hashed_password).unwrap_or(false);
+    }
+
+    // the parameters come from the hash itself, so older settings still verify
+    match PasswordHash::new(hashed_password) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether a hash should be replaced on the next successful login: bcrypt, another
+/// Argon2 variant, or Argon2id with different parameters than configured now.
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    if is_bcrypt(hashed_password) {
+        return true;
+    }
+
+    let parsed = match PasswordHash::new(hashed_password) {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+
+    if parsed.algorithm != Algorithm::Argon2id.ident() || parsed.version != Some(Version::V0x13.into()) {
+        return true;
+    }
+
+    match Params::try_from(&parsed) {
+        Ok(current) => {
+            let wanted = params();
+            current.m_cost() != wanted.m_cost() || current.t_cost() != wanted.t_cost() || current.p_cost() != wanted.p_cost()
+        }
+        Err(_) => true,
+    }
+}
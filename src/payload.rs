@@ -0,0 +1,49 @@
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+
+use actix_web::{dev, error, web, Error, FromRequest, HttpMessage, HttpRequest};
+use serde::de::DeserializeOwned;
+
+/// Request body extractor that picks a deserializer based on the `Content-Type` header.
+///
+/// Accepts `application/json` and `application/x-www-form-urlencoded`, plus
+/// `application/msgpack` when the `msgpack` feature is enabled.
+#[derive(Debug)]
+pub struct Payload<T>(pub T);
+
+impl<T> Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Payload<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let content_type = req.content_type().to_lowercase();
+        let body = web::Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let body = body.await?;
+
+            match content_type.as_str() {
+                "application/json" => serde_json::from_slice(&body)
+                    .map(Payload)
+                    .map_err(|e| error::ErrorBadRequest(format!("could not parse json body: {}", e))),
+                "application/x-www-form-urlencoded" => serde_urlencoded::from_bytes(&body)
+                    .map(Payload)
+                    .map_err(|e| error::ErrorBadRequest(format!("could not parse form body: {}", e))),
+                #[cfg(feature = "msgpack")]
+                "application/msgpack" | "application/x-msgpack" => rmp_serde::from_slice(&body)
+                    .map(Payload)
+                    .map_err(|e| error::ErrorBadRequest(format!("could not parse msgpack body: {}", e))),
+                other => Err(error::ErrorUnsupportedMediaType(format!("unsupported content type: {:?}", other))),
+            }
+        })
+    }
+}
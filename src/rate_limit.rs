@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use sea_orm::DatabaseConnection;
+
+use crate::api_keys;
+use crate::auth::{credential, find_api_key, validate_token};
+use crate::client_ip::client_ip;
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATE_LIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Token bucket parameters: up to `capacity` requests at once, refilled evenly over `period`.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    capacity: u32,
+    period: Duration,
+}
+
+impl Quota {
+    pub fn per_minute(capacity: u32) -> Self {
+        Quota { capacity, period: Duration::from_secs(60) }
+    }
+
+    /// Reads `RATE_LIMIT_<GROUP>` as `requests/seconds`, e.g. `120/60`, falling back to `default`.
+    pub fn from_env(group: &str, default: Quota) -> Self {
+        std::env::var(format!("RATE_LIMIT_{}", group.to_uppercase()))
+            .ok()
+            .and_then(|value| {
+                let (capacity, secs) = value.split_once('/')?;
+                Some(Quota {
+                    capacity: capacity.trim().parse().ok()?,
+                    period: Duration::from_secs(secs.trim().parse().ok()?),
+                })
+            })
+            .filter(|quota| quota.capacity > 0 && !quota.period.is_zero())
+            .unwrap_or(default)
+    }
+
+    fn rate(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Debug)]
+struct Decision {
+    allowed: bool,
+    limit: u32,
+    tokens: f64,
+    rate: f64,
+}
+
+impl Decision {
+    fn new(quota: Quota, allowed: bool, tokens: f64) -> Self {
+        Decision { allowed, limit: quota.capacity, tokens, rate: quota.rate() }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.tokens.floor().max(0.0) as u64
+    }
+
+    /// Seconds until the bucket is full again.
+    fn reset(&self) -> u64 {
+        ((self.limit as f64 - self.tokens) / self.rate).ceil().max(0.0) as u64
+    }
+
+    /// Seconds until the next token is available.
+    fn retry_after(&self) -> u64 {
+        ((1.0 - self.tokens) / self.rate).ceil().max(1.0) as u64
+    }
+
+    /// Writes the `RateLimit-*` headers, leaving them alone if an inner limiter
+    /// already reported a stricter one.
+    fn write_headers(&self, headers: &mut HeaderMap) {
+        let stricter = headers
+            .get(&RATE_LIMIT_REMAINING)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+            .is_some_and(|remaining| remaining <= self.remaining());
+
+        if stricter {
+            return;
+        }
+
+        headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(self.remaining()));
+        headers.insert(RATE_LIMIT_RESET, HeaderValue::from(self.reset()));
+    }
+}
+
+#[derive(Debug)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+/// Where buckets are kept. The in-memory store is per process; enable the `redis`
+/// feature and set `RATE_LIMIT_REDIS_URL` to share limits between instances.
+pub enum RateLimitStore {
+    Memory(Mutex<HashMap<String, Bucket>>),
+    #[cfg(feature = "redis")]
+    Redis(redis::aio::ConnectionManager),
+}
+
+/// Buckets kept in memory before full ones are dropped.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+#[cfg(feature = "redis")]
+const TAKE_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((capacity - tokens) / rate * 1000) + 1000)
+return {allowed, tostring(tokens)}
+"#;
+
+impl RateLimitStore {
+    pub async fn from_env() -> Self {
+        #[cfg(feature = "redis")]
+        if let Ok(url) = std::env::var("RATE_LIMIT_REDIS_URL") {
+            let client = redis::Client::open(url).expect("invalid RATE_LIMIT_REDIS_URL");
+            let manager = redis::aio::ConnectionManager::new(client)
+                .await
+                .expect("could not connect to rate limit redis");
+            return RateLimitStore::Redis(manager);
+        }
+
+        RateLimitStore::Memory(Mutex::new(HashMap::new()))
+    }
+
+    async fn take(&self, key: &str, quota: Quota) -> Result<Decision, String> {
+        match self {
+            RateLimitStore::Memory(buckets) => {
+                let now = Instant::now();
+                let mut buckets = buckets.lock().unwrap();
+
+                if buckets.len() >= MAX_MEMORY_BUCKETS && !buckets.contains_key(key) {
+                    buckets.retain(|_, bucket| bucket.full_at > now);
+                }
+
+                let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+                    tokens: quota.capacity as f64,
+                    updated: now,
+                    full_at: now,
+                });
+
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * quota.rate()).min(quota.capacity as f64);
+                bucket.updated = now;
+
+                let allowed = bucket.tokens >= 1.0;
+                if allowed {
+                    bucket.tokens -= 1.0;
+                }
+
+                let missing = quota.capacity as f64 - bucket.tokens;
+                bucket.full_at = now + Duration::from_secs_f64(missing / quota.rate());
+
+                Ok(Decision::new(quota, allowed, bucket.tokens))
+            }
+            #[cfg(feature = "redis")]
+            RateLimitStore::Redis(manager) => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs_f64();
+
+                let (allowed, tokens): (i32, String) = redis::Script::new(TAKE_SCRIPT)
+                    .key(format!("rate_limit:{}", key))
+                    .arg(quota.capacity)
+                    .arg(quota.rate())
+                    .arg(now)
+                    .invoke_async(&mut manager.clone())
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                Ok(Decision::new(quota, allowed == 1, tokens.parse().unwrap_or(0.0)))
+            }
+        }
+    }
+}
+
+/// How long the owner of an API key is remembered, so the limiters don't query for it
+/// on every request.
+const CHECKED_KEY_TTL: Duration = Duration::from_secs(60);
+
+/// Checked keys kept in memory before expired ones are dropped.
+const MAX_CHECKED_KEYS: usize = 10_000;
+
+/// An API key looked up recently, with its owner if it was valid.
+struct CheckedKey {
+    user_id: Option<i32>,
+    expires: Instant,
+}
+
+/// Recently checked API keys, by hash.
+fn checked_keys() -> &'static Mutex<HashMap<String, CheckedKey>> {
+    static CHECKED_KEYS: OnceLock<Mutex<HashMap<String, CheckedKey>>> = OnceLock::new();
+    CHECKED_KEYS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Whom a request is charged to.
+enum Client {
+    /// A bucket key, `user:{id}` or `ip:{address}`.
+    Known(String),
+    /// An API key that hasn't been checked recently.
+    UncheckedApiKey(String),
+}
+
+fn ip_key(req: &HttpRequest) -> String {
+    match client_ip(req) {
+        Some(ip) => format!("ip:{}", ip),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+/// Identifies the caller by user id when they send a valid token or API key, else by
+/// IP address. API keys count towards their owner's budget, so minting more keys
+/// doesn't buy more requests, and made-up keys are charged to the IP.
+fn client(req: &HttpRequest) -> Client {
+    if let Some(credential) = credential(req) {
+        if credential.starts_with(api_keys::KEY_PREFIX) {
+            let checked = checked_keys()
+                .lock()
+                .unwrap()
+                .get(&api_keys::hash(credential))
+                .filter(|checked| checked.expires > Instant::now())
+                .map(|checked| checked.user_id);
+
+            return match checked {
+                Some(Some(user_id)) => Client::Known(format!("user:{}", user_id)),
+                Some(None) => Client::Known(ip_key(req)),
+                None => Client::UncheckedApiKey(credential.to_owned()),
+            };
+        }
+
+        if let Ok(claims) = validate_token(credential) {
+            return Client::Known(format!("user:{}", claims.user_id));
+        }
+    }
+
+    Client::Known(ip_key(req))
+}
+
+/// Looks up the owner of an API key and remembers the answer for `CHECKED_KEY_TTL`.
+async fn check_api_key(req: &HttpRequest, key: &str) -> Option<i32> {
+    let conn = req.app_data::<web::Data<DatabaseConnection>>()?;
+    let user_id = find_api_key(conn, key).await.map(|api_key| api_key.user_id);
+
+    let now = Instant::now();
+    let mut checked = checked_keys().lock().unwrap();
+
+    if checked.len() >= MAX_CHECKED_KEYS {
+        checked.retain(|_, checked| checked.expires > now);
+    }
+    if checked.len() < MAX_CHECKED_KEYS {
+        checked.insert(api_keys::hash(key), CheckedKey { user_id, expires: now + CHECKED_KEY_TTL });
+    }
+
+    user_id
+}
+
+/// Middleware applying a token bucket per client to everything it wraps.
+///
+/// Buckets are namespaced by `group`, so the same client has separate budgets for
+/// each wrapped scope. Requests pass through untouched if no `RateLimitStore` is
+/// registered as app data.
+pub struct RateLimit {
+    group: &'static str,
+    quota: Quota,
+}
+
+impl RateLimit {
+    /// `default` can be overridden with the `RATE_LIMIT_<GROUP>` env var.
+    pub fn new(group: &'static str, default: Quota) -> Self {
+        RateLimit { group, quota: Quota::from_env(group, default) }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            group: self.group,
+            quota: self.quota,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    group: &'static str,
+    quota: Quota,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let group = self.group;
+        let quota = self.quota;
+
+        Box::pin(async move {
+            let store = match req.app_data::<web::Data<RateLimitStore>>() {
+                Some(store) => store.clone(),
+                None => return service.call(req).await.map(ServiceResponse::map_into_left_body),
+            };
+
+            let decision = match client(req.request()) {
+                Client::Known(client) => store.take(&format!("{}:{}", group, client), quota).await,
+                // the IP pays before an unknown key is looked up, so made-up keys can't
+                // run queries or get a fresh bucket once it is spent
+                Client::UncheckedApiKey(api_key) => {
+                    match store.take(&format!("{}:{}", group, ip_key(req.request())), quota).await {
+                        Ok(decision) if decision.allowed => match check_api_key(req.request(), &api_key).await {
+                            Some(user_id) => store.take(&format!("{}:user:{}", group, user_id), quota).await,
+                            None => Ok(decision),
+                        },
+                        decision => decision,
+                    }
+                }
+            };
+
+            // a broken store shouldn't take the whole API down with it
+            let decision = match decision {
+                Ok(decision) => decision,
+                Err(e) => {
+                    tracing::error!("rate limit store failed: {}", e);
+                    return service.call(req).await.map(ServiceResponse::map_into_left_body);
+                }
+            };
+
+            if !decision.allowed {
+                let mut response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, decision.retry_after()))
+                    .body("rate limit exceeded");
+                decision.write_headers(response.headers_mut());
+
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = service.call(req).await?;
+            decision.write_headers(res.headers_mut());
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,223 @@
+use actix_web::{web, HttpResponse, get, post, delete, Responder, HttpRequest};
+use actix_web::http::header;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
+
+use sea_orm::*;
+use sea_orm::prelude::DateTimeWithTimeZone;
+
+use entities::api_key::Entity as ApiKey;
+
+use crate::api_keys;
+use crate::auth::{authenticate, SCOPES};
+use crate::payload::Payload;
+use crate::validation::validation_error;
+
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct ApiKeyForm {
+    #[validate(length(min = 1, max = 100, message = "name must be between 1 and 100 characters"))]
+    name: String,
+    #[validate(length(min = 1, message = "at least one scope is required"), custom = "validate_scopes")]
+    scopes: Vec<String>,
+}
+
+fn validate_scopes(scopes: &[String]) -> Result<(), ValidationError> {
+    if scopes.iter().all(|scope| SCOPES.contains(&scope.as_str())) {
+        return Ok(());
+    }
+
+    let mut error = ValidationError::new("unknown_scope");
+    error.message = Some(format!("scopes must be any of: {}", SCOPES.join(", ")).into());
+    Err(error)
+}
+
+/// An API key as shown to its owner, never including the hash.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct ApiKeyView {
+    id: i32,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    #[schema(value_type = String, format = DateTime)]
+    created_at: DateTimeWithTimeZone,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    last_used_at: Option<DateTimeWithTimeZone>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    revoked_at: Option<DateTimeWithTimeZone>,
+}
+
+impl From<entities::api_key::Model> for ApiKeyView {
+    fn from(api_key: entities::api_key::Model) -> Self {
+        ApiKeyView {
+            id: api_key.id,
+            name: api_key.name,
+            prefix: api_key.prefix,
+            scopes: api_key.scopes.split_whitespace().map(str::to_owned).collect(),
+            created_at: api_key.created_at,
+            last_used_at: api_key.last_used_at,
+            revoked_at: api_key.revoked_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct CreatedApiKey {
+    #[serde(flatten)]
+    api_key: ApiKeyView,
+    /// The full key, only returned here.
+    key: String,
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "api keys",
+    request_body = ApiKeyForm,
+    responses(
+        (status = 201, description = "Key created. The full key is only ever shown in this response", body = CreatedApiKey, headers(("Location" = String, description = "Where the key can be read and revoked"))),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 422, description = "Invalid key", body = ValidationErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/me/api-keys")]
+async fn create(conn: web::Data<DatabaseConnection>, key_form: Payload<ApiKeyForm>, req: HttpRequest) -> impl Responder {
+
+    // keys can't mint other keys, so this needs a real session
+    let user = match authenticate(conn.as_ref(), &req, false, None).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = key_form.validate() {
+        return validation_error(e);
+    }
+
+    let new_key = api_keys::generate();
+
+    let mut scopes = key_form.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+
+    let api_key = entities::api_key::ActiveModel {
+        user_id: Set(user.user_id),
+        name: Set(key_form.name.clone()),
+        prefix: Set(new_key.prefix),
+        key_hash: Set(new_key.hash),
+        scopes: Set(scopes.join(" ")),
+        created_at: Set(Utc::now().into()),
+        ..Default::default()
+    }
+    .insert(conn.as_ref())
+    .await
+    .expect("could not insert api key");
+
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/users/me/api-keys/{}", api_key.id)))
+        .json(CreatedApiKey { api_key: api_key.into(), key: new_key.key })
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "api keys",
+    responses(
+        (status = 200, description = "The caller's keys, including revoked ones", body = [ApiKeyView]),
+        (status = 401, description = "Not signed in with a token"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/me/api-keys")]
+async fn get_all(conn: web::Data<DatabaseConnection>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, false, None).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let api_keys = ApiKey::find()
+        .filter(entities::api_key::Column::UserId.eq(user.user_id))
+        .order_by_asc(entities::api_key::Column::Id)
+        .all(conn.as_ref())
+        .await
+        .expect("could not find api keys");
+
+    HttpResponse::Ok().json(api_keys.into_iter().map(ApiKeyView::from).collect::<Vec<_>>())
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "api keys",
+    params(("id" = i32, Path, description = "Id of the key")),
+    responses(
+        (status = 200, description = "One of the caller's keys, including revoked ones", body = ApiKeyView),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 404, description = "No such key"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/me/api-keys/{id}")]
+async fn get_by_id(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, false, None).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let api_key = ApiKey::find_by_id(*id)
+        .filter(entities::api_key::Column::UserId.eq(user.user_id))
+        .one(conn.as_ref())
+        .await
+        .expect("could not find api key");
+
+    match api_key {
+        Some(api_key) => HttpResponse::Ok().json(ApiKeyView::from(api_key)),
+        None => HttpResponse::NotFound().body(format!("api key with id: {} not found", id)),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "api keys",
+    params(("id" = i32, Path, description = "Id of the key")),
+    responses(
+        (status = 200, description = "The revoked key", body = ApiKeyView),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 404, description = "No such key"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("/me/api-keys/{id}")]
+async fn revoke(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, false, None).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let api_key = ApiKey::find_by_id(*id)
+        .filter(entities::api_key::Column::UserId.eq(user.user_id))
+        .one(conn.as_ref())
+        .await
+        .expect("could not find api key");
+
+    match api_key {
+        Some(api_key) if api_key.revoked_at.is_some() => HttpResponse::Ok().json(ApiKeyView::from(api_key)),
+        Some(api_key) => {
+            let mut api_key: entities::api_key::ActiveModel = api_key.into();
+            api_key.revoked_at = Set(Some(Utc::now().into()));
+
+            let api_key = api_key.update(conn.as_ref()).await.expect("could not revoke api key");
+
+            HttpResponse::Ok().json(ApiKeyView::from(api_key))
+        }
+        None => HttpResponse::NotFound().body(format!("api key with id: {} not found", id)),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(create);
+    cfg.service(get_all);
+    cfg.service(get_by_id);
+    cfg.service(revoke);
+}
@@ -0,0 +1,212 @@
+use actix_web::{web, HttpResponse, get, Responder, HttpRequest};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use sea_orm::*;
+
+use entities::audit_log::Entity as AuditLog;
+
+use crate::auth::authenticate;
+
+/// Rows read per query while exporting.
+const EXPORT_BATCH_SIZE: u64 = 500;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Filters {
+    actor_id: Option<i32>,
+    /// e.g. `post.publish` or `user.unlock`.
+    action: Option<String>,
+    /// `post` or `user`.
+    target_type: Option<String>,
+    target_id: Option<i32>,
+    /// Only entries from this time on, as RFC 3339.
+    since: Option<DateTime<Utc>>,
+    /// Only entries before this time, as RFC 3339.
+    until: Option<DateTime<Utc>>,
+}
+
+impl Filters {
+    fn apply(&self, mut query: Select<AuditLog>) -> Select<AuditLog> {
+        if let Some(actor_id) = self.actor_id {
+            query = query.filter(entities::audit_log::Column::ActorId.eq(actor_id));
+        }
+        if let Some(action) = &self.action {
+            query = query.filter(entities::audit_log::Column::Action.eq(action.as_str()));
+        }
+        if let Some(target_type) = &self.target_type {
+            query = query.filter(entities::audit_log::Column::TargetType.eq(target_type.as_str()));
+        }
+        if let Some(target_id) = self.target_id {
+            query = query.filter(entities::audit_log::Column::TargetId.eq(target_id));
+        }
+        if let Some(since) = self.since {
+            query = query.filter(entities::audit_log::Column::CreatedAt.gte(since));
+        }
+        if let Some(until) = self.until {
+            query = query.filter(entities::audit_log::Column::CreatedAt.lt(until));
+        }
+        query
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    page: Option<u64>,
+    /// Between 1 and 100, 50 by default.
+    entries_per_page: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportParams {
+    /// `csv` or `ndjson`.
+    format: String,
+}
+
+#[utoipa::path(
+    context_path = "/audit-log",
+    tag = "audit log",
+    params(Filters, PageParams),
+    responses(
+        (status = 200, description = "A page of audit log entries, newest first, and the number of pages", body = AuditPage),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API keys can't read the audit log"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("")]
+async fn get_all(conn: web::Data<DatabaseConnection>, filters: web::Query<Filters>, params: web::Query<PageParams>, req: HttpRequest) -> impl Responder {
+
+    if let Err(response) = authenticate(conn.as_ref(), &req, true, None).await {
+        return response;
+    }
+
+    let page = params.page.unwrap_or(1).max(1);
+    let entries_per_page = params.entries_per_page.unwrap_or(50).clamp(1, 100);
+
+    let query = filters.apply(AuditLog::find()).order_by_desc(entities::audit_log::Column::Id);
+    let paginator = query.paginate(conn.as_ref(), entries_per_page);
+
+    let num_pages = paginator.num_pages().await.expect("could not count audit log entries");
+
+    match paginator.fetch_page(page - 1).await {
+        Ok(entries) => HttpResponse::Ok().json((entries, num_pages)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not fetch audit log: {}", e)),
+    }
+}
+
+/// Quotes a CSV field if it needs it. Fields that a spreadsheet would run as a formula
+/// get a leading `'`, since user agents and snapshots are whatever clients sent.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn csv_row(entry: &entities::audit_log::Model) -> String {
+    let json = |value: &Option<serde_json::Value>| value.as_ref().map(ToString::to_string).unwrap_or_default();
+
+    let fields = [
+        entry.id.to_string(),
+        entry.created_at.to_rfc3339(),
+        entry.actor_id.map(|id| id.to_string()).unwrap_or_default(),
+        entry.action.clone(),
+        entry.target_type.clone(),
+        entry.target_id.to_string(),
+        entry.ip.clone().unwrap_or_default(),
+        entry.user_agent.clone().unwrap_or_default(),
+        json(&entry.before),
+        json(&entry.after),
+    ];
+
+    let mut row = fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(",");
+    row.push_str("\r\n");
+    row
+}
+
+#[utoipa::path(
+    context_path = "/audit-log",
+    tag = "audit log",
+    params(Filters, ExportParams),
+    responses(
+        (status = 200, description = "Every matching entry, oldest first, as CSV with a header row or as one JSON object per line", content_type = "text/csv"),
+        (status = 400, description = "Unknown format"),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API keys can't read the audit log"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/export")]
+async fn export(conn: web::Data<DatabaseConnection>, filters: web::Query<Filters>, params: web::Query<ExportParams>, req: HttpRequest) -> impl Responder {
+
+    if let Err(response) = authenticate(conn.as_ref(), &req, true, None).await {
+        return response;
+    }
+
+    let (content_type, extension, csv) = match params.format.as_str() {
+        "csv" => ("text/csv; charset=utf-8", "csv", true),
+        "ndjson" => ("application/x-ndjson", "ndjson", false),
+        _ => return HttpResponse::BadRequest().body("format must be one of: csv, ndjson"),
+    };
+
+    let header = if csv { "id,created_at,actor_id,action,target_type,target_id,ip,user_agent,before,after\r\n" } else { "" };
+    let query = filters.into_inner().apply(AuditLog::find());
+
+    // reads the log in batches by id, so large exports aren't held in memory
+    let batches = stream::unfold(Some(0), move |after| {
+        let conn = conn.clone();
+        let query = query.clone();
+
+        async move {
+            let after = after?;
+
+            let entries = query
+                .filter(entities::audit_log::Column::Id.gt(after))
+                .order_by_asc(entities::audit_log::Column::Id)
+                .limit(EXPORT_BATCH_SIZE)
+                .all(conn.as_ref())
+                .await;
+
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => return Some((Err(actix_web::error::ErrorInternalServerError(e)), None)),
+            };
+
+            let last = entries.last()?.id;
+            let body: String = entries
+                .iter()
+                .map(|entry| if csv { csv_row(entry) } else { format!("{}\n", serde_json::to_string(entry).unwrap()) })
+                .collect();
+
+            Some((Ok(web::Bytes::from(body)), Some(last)))
+        }
+    });
+
+    let body = stream::iter([Ok::<_, actix_web::Error>(web::Bytes::from_static(header.as_bytes()))]).chain(batches);
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("audit-log.{}", extension))],
+        })
+        .streaming(body)
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_all);
+    cfg.service(export);
+}
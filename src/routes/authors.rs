@@ -0,0 +1,58 @@
+use actix_web::{web, HttpResponse, get, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use sea_orm::*;
+use sea_orm::sea_query::{Expr, Func};
+
+use entities::post::Entity as Post;
+use entities::user::Entity as User;
+
+use super::users::PublicUser;
+
+/// What readers see about whoever wrote a post.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct AuthorProfile {
+    #[serde(flatten)]
+    user: PublicUser,
+    post_count: u64,
+}
+
+#[utoipa::path(
+    context_path = "/authors",
+    tag = "authors",
+    params(("username" = String, Path, description = "Matched case-insensitively")),
+    responses(
+        (status = 200, description = "The author and how many posts they have published", body = AuthorProfile),
+        (status = 404, description = "No such active author"),
+    ),
+)]
+#[get("/{username}")]
+async fn get_by_username(conn: web::Data<DatabaseConnection>, username: web::Path<String>) -> impl Responder {
+
+    let user = User::find()
+        .filter(Expr::expr(Func::lower(Expr::col(entities::user::Column::Username))).eq(username.to_lowercase()))
+        .filter(entities::user::Column::IsActive.eq(true))
+        .one(conn.as_ref())
+        .await
+        .expect("could not find user");
+
+    let user = match user {
+        Some(user) => user,
+        None => return HttpResponse::NotFound().body(format!("author: {} not found", username)),
+    };
+
+    let post_count = Post::find()
+        .filter(entities::post::Column::UserId.eq(user.id))
+        .filter(entities::post::Column::IsPublished.eq(true))
+        .filter(entities::post::Column::DeletedAt.is_null())
+        .count(conn.as_ref())
+        .await
+        .expect("could not count posts");
+
+    HttpResponse::Ok().json(AuthorProfile { user: (&user).into(), post_count })
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_by_username);
+}
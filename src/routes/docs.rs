@@ -0,0 +1,240 @@
+use actix_web::{web, HttpResponse, get, Responder};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ArrayBuilder, ObjectBuilder, OneOfBuilder, Ref, RefOr, Schema, SchemaType};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use super::{api_keys, audit_log, authors, graphql, oidc, posts, trash, two_factor, users, well_known};
+use crate::validation::{FieldError, ValidationErrorBody};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Blog API", description = "Posts, users and authentication."),
+    paths(
+        posts::get_all, posts::get_by_id, posts::get_by_slug, posts::create, posts::update, posts::partial_update, posts::delete, posts::restore,
+        trash::get_all,
+        users::get_all, users::get_by_id, users::create, users::update, users::delete,
+        users::login, users::unlock, users::update_profile, users::logout,
+        two_factor::enroll, two_factor::qr_code, two_factor::activate, two_factor::disable, two_factor::challenge,
+        api_keys::create, api_keys::get_all, api_keys::get_by_id, api_keys::revoke,
+        oidc::login, oidc::callback,
+        authors::get_by_username,
+        audit_log::get_all, audit_log::export,
+        well_known::jwks,
+        graphql::execute, graphql::graphiql,
+        openapi_json, redoc,
+    ),
+    components(schemas(
+        entities::post::Model, posts::PostWithAuthor, posts::PostForm, PostPage, TrashPage,
+        users::PublicUser, users::PrivateUser, users::UserView, users::ListedUser, UserPage,
+        users::UserForm, users::LoginForm, users::ProfileForm, users::TwoFactorChallenge,
+        two_factor::CodeForm, two_factor::ChallengeForm, two_factor::Enrollment, two_factor::RecoveryCodes,
+        api_keys::ApiKeyForm, api_keys::ApiKeyView, api_keys::CreatedApiKey,
+        authors::AuthorProfile,
+        entities::audit_log::Model, AuditPage,
+        ValidationErrorBody, FieldError,
+    )),
+    modifiers(&SecurityAddon),
+)]
+pub struct ApiDoc;
+
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).bearer_format("JWT").build()),
+        );
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))));
+    }
+}
+
+/// Listings are sent as `[items, number_of_pages]`, which OpenAPI 3.0 can't describe
+/// as a tuple, so this is an array of two with either shape.
+fn page_schema(item: &str, description: &str) -> RefOr<Schema> {
+    let items = ArrayBuilder::new().items(Ref::from_schema_name(item));
+    let num_pages = ObjectBuilder::new().schema_type(SchemaType::Integer);
+
+    ArrayBuilder::new()
+        .description(Some(description))
+        .items(OneOfBuilder::new().item(items).item(num_pages))
+        .min_items(Some(2))
+        .max_items(Some(2))
+        .into()
+}
+
+/// Schema of the post listing.
+pub(super) struct PostPage;
+
+impl<'s> ToSchema<'s> for PostPage {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        ("PostPage", page_schema("PostWithAuthor", "`[posts, number_of_pages]`"))
+    }
+}
+
+/// Schema of the trash listing.
+pub(super) struct TrashPage;
+
+impl<'s> ToSchema<'s> for TrashPage {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        ("TrashPage", page_schema("Post", "`[posts, number_of_pages]`"))
+    }
+}
+
+/// Schema of the audit log listing.
+pub(super) struct AuditPage;
+
+impl<'s> ToSchema<'s> for AuditPage {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        ("AuditPage", page_schema("AuditEntry", "`[entries, number_of_pages]`"))
+    }
+}
+
+/// Schema of the admin user listing.
+pub(super) struct UserPage;
+
+impl<'s> ToSchema<'s> for UserPage {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        ("UserPage", page_schema("ListedUser", "`[users, number_of_pages]`"))
+    }
+}
+
+#[utoipa::path(
+    tag = "docs",
+    responses((status = 200, description = "This OpenAPI document", body = Object)),
+)]
+#[get("/openapi.json")]
+async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    tag = "docs",
+    responses((status = 200, description = "The OpenAPI document rendered with Redoc", content_type = "text/html")),
+)]
+#[get("/docs")]
+async fn redoc() -> impl Responder {
+    // Redoc is pinned to a release, so the script this page runs can't change under us
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(
+        r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Blog API</title>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+  </head>
+  <body>
+    <redoc spec-url="/openapi.json"></redoc>
+    <script src="https://cdn.redoc.ly/redoc/v2.1.3/bundles/redoc.standalone.js" crossorigin="anonymous"></script>
+  </body>
+</html>
+"#,
+    )
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(openapi_json);
+    cfg.service(redoc);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, web, App, HttpResponse};
+    use utoipa::openapi::path::PathItemType;
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+    use crate::routes::ROUTES;
+
+    /// What the probe app answers for anything no route handles.
+    const UNROUTED: StatusCode = StatusCode::IM_A_TEAPOT;
+
+    const METHODS: [(Method, PathItemType); 5] = [
+        (Method::GET, PathItemType::Get),
+        (Method::POST, PathItemType::Post),
+        (Method::PUT, PathItemType::Put),
+        (Method::PATCH, PathItemType::Patch),
+        (Method::DELETE, PathItemType::Delete),
+    ];
+
+    /// `pattern` with each parameter, named without its regex, replaced by `value`.
+    fn fill(pattern: &str, value: impl Fn(&str) -> String) -> String {
+        let mut path = String::new();
+        let mut rest = pattern;
+
+        while let Some((before, after)) = rest.split_once('{') {
+            let (param, after) = after.split_once('}').unwrap();
+            path.push_str(before);
+            path.push_str(&value(param.split(':').next().unwrap()));
+            rest = after;
+        }
+
+        path + rest
+    }
+
+    #[actix_web::test]
+    async fn routes_match_the_spec() {
+        let spec = ApiDoc::openapi();
+
+        let documented: BTreeSet<String> = spec
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                item.operations.keys().map(move |item_type| {
+                    let (method, _) = METHODS.iter().find(|(_, t)| t == item_type).expect("unexpected method in the spec");
+                    format!("{} {}", method, path)
+                })
+            })
+            .collect();
+        let listed: BTreeSet<String> = ROUTES.iter().map(|(method, path)| format!("{} {}", method, path)).collect();
+
+        let undocumented: Vec<&String> = listed.difference(&documented).collect();
+        let unlisted: Vec<&String> = documented.difference(&listed).collect();
+
+        assert!(undocumented.is_empty(), "routes missing from the OpenAPI spec: {:?}", undocumented);
+        assert!(unlisted.is_empty(), "documented routes missing from `ROUTES`: {:?}", unlisted);
+    }
+
+    #[actix_web::test]
+    async fn every_route_is_routed() {
+        let app = test::init_service(
+            App::new()
+                .configure(crate::routes::init_routes)
+                .default_service(web::to(|| async { HttpResponse::build(UNROUTED).finish() })),
+        )
+        .await;
+
+        let response = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        let resource_map = response.request().resource_map().clone();
+
+        let mut unrouted = Vec::new();
+
+        for (method, path) in ROUTES {
+            let uri = fill(path, |_| "1".to_string());
+
+            // an earlier route for the same method with a looser pattern would take the
+            // request instead, e.g. `/posts/{id}` in front of `/posts/{slug}`. The router
+            // doesn't look at methods here, since other methods fall through their guards
+            let matched = resource_map.match_pattern(&uri).map(|pattern| fill(&pattern, |param| format!("{{{}}}", param)));
+            if let Some(other) = matched.filter(|other| other != path && ROUTES.contains(&(*method, other.as_str()))) {
+                unrouted.push(format!("{} {} (taken by {})", method, path, other));
+                continue;
+            }
+
+            let request = test::TestRequest::default().method(method.parse().unwrap()).uri(&uri).to_request();
+            let status = test::call_service(&app, request).await.status();
+
+            if status == UNROUTED || status == StatusCode::METHOD_NOT_ALLOWED {
+                unrouted.push(format!("{} {} (answered {})", method, path, status));
+            }
+        }
+
+        assert!(unrouted.is_empty(), "routes in `ROUTES` the router doesn't serve: {}", unrouted.join(", "));
+    }
+}
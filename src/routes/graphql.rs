@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+
+use actix_web::{web, HttpResponse, get, post, Responder, HttpRequest};
+use async_graphql::connection::{self, Connection, Edge};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, EmptySubscription, Error, ErrorExtensions, Json, Object, Result, Schema};
+use validator::Validate;
+
+use sea_orm::*;
+use sea_orm::sea_query::{Expr, Func};
+
+use entities::post::Entity as Post;
+use entities::user::Entity as User;
+
+use super::posts::{can_trash, insert_post, require_if_match, trash_post, update_post, PostForm};
+use crate::audit::Actor;
+use crate::auth::{self, authenticate_credential, Identity, POSTS_DELETE, POSTS_WRITE, USERS_READ};
+use crate::validation::ValidationErrorBody;
+
+pub type BlogSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Page size of connections when neither `first` nor `last` is given.
+const DEFAULT_PAGE_SIZE: usize = 10;
+const MAX_PAGE_SIZE: usize = 100;
+/// Page size cap of connections nested under another node, like `User.posts`,
+/// which run a query for every node of the outer page.
+const MAX_NESTED_PAGE_SIZE: usize = 20;
+
+pub fn build_schema(conn: DatabaseConnection) -> BlogSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(conn)
+        .limit_depth(10)
+        .limit_complexity(2000)
+        .finish()
+}
+
+/// Complexity of a connection field: its selection counted once per node it can return.
+fn connection_complexity(first: Option<i32>, last: Option<i32>, max_page_size: usize, child_complexity: usize) -> usize {
+    let page_size = first.or(last).map_or(DEFAULT_PAGE_SIZE, |n| n.max(0) as usize).min(max_page_size);
+    page_size.max(1).saturating_mul(child_complexity)
+}
+
+/// The credential sent with a request, checked only by fields that need it.
+struct Credential(Option<String>);
+
+/// Batches user lookups, so the authors of a page of posts take a single query.
+pub struct UserLoader(DatabaseConnection);
+
+impl Loader<i32> for UserLoader {
+    type Value = entities::user::Model;
+    type Error = String;
+
+    async fn load(&self, keys: &[i32]) -> Result<HashMap<i32, Self::Value>, Self::Error> {
+        let users = User::find()
+            .filter(entities::user::Column::Id.is_in(keys.iter().copied()))
+            .all(&self.0)
+            .await
+            .map_err(|e| format!("could not find users: {}", e))?;
+
+        Ok(users.into_iter().map(|user| (user.id, user)).collect())
+    }
+}
+
+fn error(code: &'static str, message: impl Into<String>) -> Error {
+    Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+/// Runs the same checks as the REST routes, reporting failures with their status as the `code`.
+async fn authenticate(ctx: &Context<'_>, needs_admin: bool, scope: Option<&str>) -> Result<Identity> {
+    let conn = ctx.data_unchecked::<DatabaseConnection>();
+    let credential = ctx.data_unchecked::<Credential>();
+
+    authenticate_credential(conn, credential.0.as_deref(), needs_admin, scope)
+        .await
+        .map_err(|e| match e {
+            auth::AuthError::Unauthorized(message) => error("UNAUTHORIZED", message),
+            auth::AuthError::Forbidden(message) => error("FORBIDDEN", message),
+        })
+}
+
+fn validate(post_form: &PostForm) -> Result<()> {
+    post_form.validate().map_err(|e| {
+        let body = serde_json::to_value(ValidationErrorBody::from(e)).unwrap();
+        Error::new("invalid post").extend_with(|_, extensions| {
+            extensions.set("code", "UNPROCESSABLE_ENTITY");
+            extensions.set("errors", async_graphql::Value::from_json(body["errors"].clone()).unwrap_or_default());
+        })
+    })
+}
+
+fn changed(id: i32) -> Error {
+    error("PRECONDITION_FAILED", format!("post with id: {} has changed since it was read", id))
+}
+
+/// Loads a post for a mutation, checking it is still at `version` if one was given,
+/// and that one was given while `REQUIRE_IF_MATCH` is on.
+async fn find_current(conn: &DatabaseConnection, id: i32, version: Option<i32>) -> Result<entities::post::Model> {
+    let post = Post::find_by_id(id).filter(entities::post::Column::DeletedAt.is_null()).one(conn).await?;
+
+    match post {
+        Some(_) if version.is_none() && require_if_match() => Err(error("PRECONDITION_REQUIRED", "a version is required")),
+        Some(post) if version.is_none_or(|version| version == post.version) => Ok(post),
+        Some(_) => Err(changed(id)),
+        None => Err(error("NOT_FOUND", format!("post with id: {} not found", id))),
+    }
+}
+
+/// Pages through `query` by id, using post ids as cursors.
+async fn paginate_posts(
+    conn: &DatabaseConnection,
+    query: Select<Post>,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+    max_page_size: usize,
+) -> Result<Connection<i32, PostNode>> {
+    connection::query(after, before, first, last, |after: Option<i32>, before: Option<i32>, first, last| async move {
+        let mut query = query;
+        if let Some(after) = after {
+            query = query.filter(entities::post::Column::Id.gt(after));
+        }
+        if let Some(before) = before {
+            query = query.filter(entities::post::Column::Id.lt(before));
+        }
+
+        // `last` reads backwards from the end, then the page is put back in order
+        let backwards = first.is_none() && last.is_some();
+        let limit = first.or(last).unwrap_or(DEFAULT_PAGE_SIZE).min(max_page_size);
+
+        let query = if backwards {
+            query.order_by_desc(entities::post::Column::Id)
+        } else {
+            query.order_by_asc(entities::post::Column::Id)
+        };
+
+        let mut posts = query.limit(limit as u64 + 1).all(conn).await?;
+
+        let has_more = posts.len() > limit;
+        posts.truncate(limit);
+        if backwards {
+            posts.reverse();
+        }
+
+        let (has_previous_page, has_next_page) = if backwards {
+            (has_more, before.is_some())
+        } else {
+            (after.is_some(), has_more)
+        };
+
+        let mut connection = Connection::new(has_previous_page, has_next_page);
+        connection.edges.extend(posts.into_iter().map(|post| Edge::new(post.id, PostNode(post))));
+
+        Ok::<_, DbErr>(connection)
+    })
+    .await
+}
+
+/// A row of `entities::post`.
+pub struct PostNode(entities::post::Model);
+
+#[Object(name = "Post")]
+impl PostNode {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn slug(&self) -> Option<&str> {
+        self.0.slug.as_deref()
+    }
+
+    async fn title(&self) -> &str {
+        &self.0.title
+    }
+
+    /// The post body in Markdown.
+    async fn text(&self) -> &str {
+        &self.0.text
+    }
+
+    async fn is_published(&self) -> bool {
+        self.0.is_published
+    }
+
+    async fn excerpt(&self) -> Option<&str> {
+        self.0.excerpt.as_deref()
+    }
+
+    async fn word_count(&self) -> i32 {
+        self.0.word_count
+    }
+
+    /// Estimated minutes to read.
+    async fn reading_time(&self) -> i32 {
+        self.0.reading_time
+    }
+
+    /// Bumped on every write. Pass it back to mutations to detect concurrent edits.
+    async fn version(&self) -> i32 {
+        self.0.version
+    }
+
+    /// Whoever wrote the post, through `Relation::User`. Loaded in one batch for all
+    /// posts in the response.
+    async fn author(&self, ctx: &Context<'_>) -> Result<Option<UserNode>> {
+        let user_id = match self.0.user_id {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        let user = ctx.data_unchecked::<DataLoader<UserLoader>>().load_one(user_id).await?;
+
+        Ok(user.map(UserNode))
+    }
+}
+
+/// The public profile of a row of `entities::user`.
+pub struct UserNode(entities::user::Model);
+
+#[Object(name = "User")]
+impl UserNode {
+    async fn id(&self) -> i32 {
+        self.0.id
+    }
+
+    async fn username(&self) -> &str {
+        &self.0.username
+    }
+
+    async fn display_name(&self) -> Option<&str> {
+        self.0.display_name.as_deref()
+    }
+
+    async fn bio(&self) -> Option<&str> {
+        self.0.bio.as_deref()
+    }
+
+    async fn website(&self) -> Option<&str> {
+        self.0.website.as_deref()
+    }
+
+    async fn avatar_url(&self) -> Option<&str> {
+        self.0.avatar_url.as_deref()
+    }
+
+    /// Links keyed by network name.
+    async fn social_links(&self) -> Option<Json<serde_json::Value>> {
+        self.0.social_links.clone().map(Json)
+    }
+
+    /// The user's published posts, through `Relation::Post`, oldest first, at most 20 per page.
+    #[graphql(complexity = "connection_complexity(first, last, MAX_NESTED_PAGE_SIZE, child_complexity)")]
+    async fn posts(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<i32, PostNode>> {
+        let query = self
+            .0
+            .find_related(Post)
+            .filter(entities::post::Column::IsPublished.eq(true))
+            .filter(entities::post::Column::DeletedAt.is_null());
+
+        paginate_posts(ctx.data_unchecked::<DatabaseConnection>(), query, after, before, first, last, MAX_NESTED_PAGE_SIZE).await
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A post by `id` or `slug`, like `GET /posts/{id}` and `GET /posts/slug/{slug}`.
+    async fn post(&self, ctx: &Context<'_>, id: Option<i32>, slug: Option<String>) -> Result<Option<PostNode>> {
+        let query = match (id, slug) {
+            (Some(id), None) => Post::find().filter(entities::post::Column::Id.eq(id)),
+            (None, Some(slug)) => Post::find().filter(entities::post::Column::Slug.eq(slug)),
+            _ => return Err(error("BAD_REQUEST", "pass either id or slug")),
+        };
+        let query = query.filter(entities::post::Column::DeletedAt.is_null());
+
+        let post = query.one(ctx.data_unchecked::<DatabaseConnection>()).await?;
+
+        Ok(post.map(PostNode))
+    }
+
+    /// Published posts, oldest first.
+    #[graphql(complexity = "connection_complexity(first, last, MAX_PAGE_SIZE, child_complexity)")]
+    async fn posts(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> Result<Connection<i32, PostNode>> {
+        let query = Post::find()
+            .filter(entities::post::Column::IsPublished.eq(true))
+            .filter(entities::post::Column::DeletedAt.is_null());
+
+        paginate_posts(ctx.data_unchecked::<DatabaseConnection>(), query, after, before, first, last, MAX_PAGE_SIZE).await
+    }
+
+    /// An active author, matched case-insensitively like `GET /authors/{username}`.
+    async fn author(&self, ctx: &Context<'_>, username: String) -> Result<Option<UserNode>> {
+        let user = User::find()
+            .filter(Expr::expr(Func::lower(Expr::col(entities::user::Column::Username))).eq(username.to_lowercase()))
+            .filter(entities::user::Column::IsActive.eq(true))
+            .one(ctx.data_unchecked::<DatabaseConnection>())
+            .await?;
+
+        Ok(user.map(UserNode))
+    }
+
+    /// Any user by id. Needs signing in, like `GET /users/{id}`.
+    async fn user(&self, ctx: &Context<'_>, id: i32) -> Result<Option<UserNode>> {
+        authenticate(ctx, false, Some(USERS_READ)).await?;
+
+        let user = User::find_by_id(id).one(ctx.data_unchecked::<DatabaseConnection>()).await?;
+
+        Ok(user.map(UserNode))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Same rules as `POST /posts/`: an admin, or an API key with `posts:write`.
+    async fn create_post(&self, ctx: &Context<'_>, input: PostForm) -> Result<PostNode> {
+        let user = authenticate(ctx, true, Some(POSTS_WRITE)).await?;
+        validate(&input)?;
+
+        let actor = ctx.data_unchecked::<Actor>().as_user(user.user_id);
+
+        insert_post(ctx.data_unchecked::<DatabaseConnection>(), &actor, &input)
+            .await
+            .map(PostNode)
+            .map_err(|e| error("CONFLICT", e))
+    }
+
+    /// Same rules as `PUT /posts/{id}`. With `version`, fails instead of overwriting
+    /// anyone else's changes, like `If-Match`.
+    async fn update_post(&self, ctx: &Context<'_>, id: i32, input: PostForm, version: Option<i32>) -> Result<PostNode> {
+        let user = authenticate(ctx, true, Some(POSTS_WRITE)).await?;
+        validate(&input)?;
+
+        let conn = ctx.data_unchecked::<DatabaseConnection>();
+        let post = find_current(conn, id, version).await?;
+        let actor = ctx.data_unchecked::<Actor>().as_user(user.user_id);
+
+        match update_post(conn, &actor, post, &input).await {
+            Ok(Some(post)) => Ok(PostNode(post)),
+            Ok(None) => Err(changed(id)),
+            Err(e) => Err(error("CONFLICT", e)),
+        }
+    }
+
+    /// Same rules as `DELETE /posts/{id}`: the author or an admin moves the post to the
+    /// trash. Returns the id of the deleted post.
+    async fn delete_post(&self, ctx: &Context<'_>, id: i32, version: Option<i32>) -> Result<i32> {
+        let user = authenticate(ctx, false, Some(POSTS_DELETE)).await?;
+        let conn = ctx.data_unchecked::<DatabaseConnection>();
+
+        let post = find_current(conn, id, version).await?;
+
+        if !can_trash(&user, &post) {
+            return Err(error("UNAUTHORIZED", "user is not authorized to delete this post"));
+        }
+
+        let actor = ctx.data_unchecked::<Actor>().as_user(user.user_id);
+
+        if trash_post(conn, &actor, &post).await.is_none() {
+            return Err(changed(id));
+        }
+
+        Ok(id)
+    }
+}
+
+#[utoipa::path(
+    context_path = "/graphql",
+    tag = "graphql",
+    request_body(content = Object, description = "A GraphQL request with `query`, and optionally `variables` and `operationName`"),
+    responses((status = 200, description = "The GraphQL response, with any errors in `errors`", body = Object)),
+    security((), ("bearer" = []), ("api_key" = [])),
+)]
+#[post("")]
+async fn execute(schema: web::Data<BlogSchema>, conn: web::Data<DatabaseConnection>, request: web::Json<async_graphql::Request>, req: HttpRequest) -> impl Responder {
+
+    // loaders are per request, so nothing cached outlives it
+    let request = request
+        .into_inner()
+        .data(Credential(auth::credential(&req).map(str::to_owned)))
+        .data(Actor::new(&req, None))
+        .data(DataLoader::new(UserLoader(conn.get_ref().clone()), actix_web::rt::spawn));
+
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+#[utoipa::path(
+    context_path = "/graphql",
+    tag = "graphql",
+    responses((status = 200, description = "A GraphiQL playground", content_type = "text/html")),
+)]
+#[get("")]
+async fn graphiql() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/graphql").title("Blog API").finish())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(execute);
+    cfg.service(graphiql);
+}
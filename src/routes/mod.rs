@@ -1,13 +1,116 @@
 use actix_web::web;
 
+mod api_keys;
+mod audit_log;
+mod authors;
+mod docs;
+mod graphql;
+mod oidc;
 mod posts;
+mod trash;
+mod two_factor;
 mod users;
+mod well_known;
 
+use api_keys::init_routes as init_api_keys_routes;
+use audit_log::init_routes as init_audit_log_routes;
+use authors::init_routes as init_authors_routes;
+use docs::init_routes as init_docs_routes;
+use graphql::init_routes as init_graphql_routes;
+use oidc::init_routes as init_oidc_routes;
 use posts::init_routes as init_posts_routes;
+use trash::init_routes as init_trash_routes;
+use two_factor::init_routes as init_two_factor_routes;
 use users::init_routes as init_users_routes;
+use well_known::init_routes as init_well_known_routes;
+
+use crate::rate_limit::{Quota, RateLimit};
+
+pub use graphql::build_schema;
+
+/// Every route `init_routes` registers, with parameters named as in the OpenAPI spec.
+/// The `docs` tests check this against both the router and the spec, so a new route
+/// needs a line here as well as its `#[utoipa::path]`.
+#[cfg(test)]
+const ROUTES: &[(&str, &str)] = &[
+    ("GET", "/posts/"),
+    ("POST", "/posts/"),
+    ("GET", "/posts/{id}"),
+    ("PUT", "/posts/{id}"),
+    ("PATCH", "/posts/{id}"),
+    ("DELETE", "/posts/{id}"),
+    ("GET", "/posts/slug/{slug}"),
+    ("POST", "/posts/{id}/restore"),
+    ("GET", "/users/"),
+    ("POST", "/users/"),
+    ("GET", "/users/{id}"),
+    ("PUT", "/users/{id}"),
+    ("DELETE", "/users/{id}"),
+    ("POST", "/users/{id}/unlock"),
+    ("POST", "/users/login"),
+    ("POST", "/users/logout"),
+    ("PUT", "/users/me/profile"),
+    ("POST", "/users/me/2fa"),
+    ("DELETE", "/users/me/2fa"),
+    ("GET", "/users/me/2fa/qr.png"),
+    ("POST", "/users/me/2fa/activate"),
+    ("POST", "/users/login/2fa"),
+    ("GET", "/users/me/api-keys"),
+    ("POST", "/users/me/api-keys"),
+    ("GET", "/users/me/api-keys/{id}"),
+    ("DELETE", "/users/me/api-keys/{id}"),
+    ("GET", "/users/oidc/login"),
+    ("GET", "/users/oidc/callback"),
+    ("GET", "/trash"),
+    ("GET", "/authors/{username}"),
+    ("GET", "/audit-log"),
+    ("GET", "/audit-log/export"),
+    ("GET", "/graphql"),
+    ("POST", "/graphql"),
+    ("GET", "/.well-known/jwks.json"),
+    ("GET", "/openapi.json"),
+    ("GET", "/docs"),
+];
 
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
-    init_posts_routes(cfg);
-    init_users_routes(cfg);
-}
\ No newline at end of file
+    cfg.service(
+        web::scope("/posts")
+            .wrap(RateLimit::new("posts", Quota::per_minute(120)))
+            .configure(init_posts_routes),
+    );
+    cfg.service(
+        web::scope("/users")
+            .wrap(RateLimit::new("users", Quota::per_minute(30)))
+            .configure(init_users_routes)
+            .configure(init_two_factor_routes)
+            .configure(init_api_keys_routes)
+            .configure(init_oidc_routes),
+    );
+    // the trash is managed alongside posts, so it shares their budget
+    cfg.service(
+        web::scope("/trash")
+            .wrap(RateLimit::new("posts", Quota::per_minute(120)))
+            .configure(init_trash_routes),
+    );
+    // author pages are read alongside posts, so they share a budget
+    cfg.service(
+        web::scope("/authors")
+            .wrap(RateLimit::new("posts", Quota::per_minute(120)))
+            .configure(init_authors_routes),
+    );
+    // admin-only, so it gets the same budget as user management
+    cfg.service(
+        web::scope("/audit-log")
+            .wrap(RateLimit::new("users", Quota::per_minute(30)))
+            .configure(init_audit_log_routes),
+    );
+    // one request can ask for a lot, so it gets a tighter budget than REST reads
+    cfg.service(
+        web::scope("/graphql")
+            .wrap(RateLimit::new("graphql", Quota::per_minute(60)))
+            .configure(init_graphql_routes),
+    );
+    cfg.configure(init_well_known_routes);
+    cfg.configure(init_docs_routes);
+}
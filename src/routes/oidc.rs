@@ -0,0 +1,316 @@
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::http::header;
+use actix_web::{web, HttpResponse, get, Responder, HttpRequest};
+use chrono::Utc;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use sea_orm::*;
+use sea_orm::sea_query::{Expr, Func};
+
+use entities::user::Entity as User;
+use entities::user_identity::Entity as UserIdentity;
+
+use slugify::slugify;
+
+use super::users::{PrivateUser, TwoFactorChallenge};
+use crate::audit::{self, Actor, Entry};
+use crate::auth::{create_jwt, create_pending_jwt, decode_token, encode_token};
+use crate::db::{is_unique_violation, violates_unique};
+use crate::oidc::{IdClaims, Oidc, PendingLogin};
+use crate::validation::validate_username;
+
+/// Holds the signed `PendingLogin` between the redirect and the callback.
+const LOGIN_COOKIE: &str = "oidc_login";
+
+/// Stored instead of a password hash for users who only sign in through the provider.
+/// It isn't a valid hash, so password logins for them always fail.
+const NO_PASSWORD: &str = "!";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+fn login_cookie(value: String, oidc: &Oidc) -> Cookie<'static> {
+    Cookie::build(LOGIN_COOKIE, value)
+        .path("/users/oidc")
+        .http_only(true)
+        .secure(oidc.is_https())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(10))
+        .finish()
+}
+
+/// The unique index on `lower(username)`, from the user unique indexes migration.
+const USERNAME_INDEX: &str = "idx-user-username-lower";
+
+/// Usernames tried before giving up when concurrent sign-ups keep taking them.
+const USERNAME_ATTEMPTS: usize = 5;
+
+/// A username based on what the provider calls the user, before any suffix.
+fn username_base(claims: &IdClaims, email: &str) -> String {
+    let wanted = claims
+        .preferred_username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+
+    let base: String = slugify!(wanted).chars().take(28).collect();
+    let base = base.trim_end_matches('-');
+
+    if base.len() < 3 || validate_username(base).is_err() {
+        "user".to_string()
+    } else {
+        base.to_string()
+    }
+}
+
+/// `base`, or `base-2`, `base-3`, … whichever is the first no user has, found with
+/// a single query.
+async fn unique_username(conn: &impl ConnectionTrait, base: &str) -> Result<String, DbErr> {
+    let username = Func::lower(Expr::col(entities::user::Column::Username));
+
+    let taken: Vec<String> = User::find()
+        .select_only()
+        .column(entities::user::Column::Username)
+        .filter(
+            Condition::any()
+                .add(Expr::expr(username.clone()).eq(base))
+                .add(Expr::expr(username).like(format!("{}-%", base).as_str())),
+        )
+        .into_json()
+        .all(conn)
+        .await?
+        .iter()
+        .filter_map(|user| user["username"].as_str().map(str::to_lowercase))
+        .collect();
+
+    let username = std::iter::once(base.to_string())
+        .chain((2..).map(|n| format!("{}-{}", base, n)))
+        .find(|candidate| !taken.contains(candidate) && validate_username(candidate).is_ok())
+        .unwrap();
+
+    Ok(username)
+}
+
+/// Creates a user for a verified identity. The unique index on usernames decides
+/// between concurrent sign-ups, and the loser moves on to the next free suffix.
+async fn provision_user(txn: &DatabaseTransaction, oidc: &Oidc, claims: &IdClaims, email: &str, req: &HttpRequest) -> Result<entities::user::Model, HttpResponse> {
+    let base = username_base(claims, email);
+
+    for _ in 0..USERNAME_ATTEMPTS {
+        let username = unique_username(txn, &base).await.expect("could not find usernames");
+
+        // a failed statement aborts the transaction, so each attempt gets a savepoint
+        let attempt = txn.begin().await.expect("could not start savepoint");
+
+        let result = entities::user::ActiveModel {
+            username: Set(username),
+            email: Set(email.to_string()),
+            password: Set(NO_PASSWORD.to_string()),
+            is_active: Set(oidc.provisioning.activate),
+            is_admin: Set(false),
+            totp_enabled: Set(false),
+            ..Default::default()
+        }
+        .insert(&attempt)
+        .await;
+
+        match result {
+            Ok(user) => {
+                let entry = Entry::new("user.create", "user", user.id).after(&PrivateUser::from(&user));
+                audit::record(&attempt, &Actor::new(req, None), entry).await;
+                attempt.commit().await.expect("could not release savepoint");
+                return Ok(user);
+            }
+            Err(e) if violates_unique(&e, USERNAME_INDEX) => {
+                attempt.rollback().await.expect("could not roll back savepoint");
+            }
+            // a password account already uses this email and linking is off
+            Err(e) if is_unique_violation(&e) => {
+                return Err(HttpResponse::Conflict().body("an account with this email already exists"));
+            }
+            Err(e) => return Err(HttpResponse::InternalServerError().body(format!("could not create user: {}", e))),
+        }
+    }
+
+    Err(HttpResponse::Conflict().body("could not find a free username, try again"))
+}
+
+/// Finds the user an identity belongs to, linking or provisioning one as configured.
+async fn resolve_user(conn: &DatabaseConnection, oidc: &Oidc, claims: &IdClaims, req: &HttpRequest) -> Result<entities::user::Model, HttpResponse> {
+    let now = Utc::now();
+
+    let identity = UserIdentity::find()
+        .filter(entities::user_identity::Column::Issuer.eq(oidc.issuer.clone()))
+        .filter(entities::user_identity::Column::Subject.eq(claims.sub.clone()))
+        .one(conn)
+        .await
+        .expect("could not find identity");
+
+    if let Some(identity) = identity {
+        let user_id = identity.user_id;
+
+        let mut identity: entities::user_identity::ActiveModel = identity.into();
+        identity.email = Set(claims.email.clone());
+        identity.last_login_at = Set(now.into());
+        identity.update(conn).await.expect("could not update identity");
+
+        return User::find_by_id(user_id)
+            .one(conn)
+            .await
+            .expect("could not find user")
+            .ok_or_else(|| HttpResponse::Unauthorized().body("You are unauthorized to use this route."));
+    }
+
+    // an unverified email could belong to anyone, so it never links or provisions
+    let email = match &claims.email {
+        Some(email) if claims.email_verified => email.trim().to_lowercase(),
+        _ => return Err(HttpResponse::Forbidden().body("no account is linked to this identity")),
+    };
+
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    let existing = if oidc.provisioning.link_by_email {
+        User::find()
+            .filter(Expr::expr(Func::lower(Expr::col(entities::user::Column::Email))).eq(email.clone()))
+            .one(&txn)
+            .await
+            .expect("could not find user")
+    } else {
+        None
+    };
+
+    let user = match existing {
+        Some(user) => user,
+        None if oidc.provisioning.auto_provision && oidc.provisioning.allows_email(&email) => {
+            provision_user(&txn, oidc, claims, &email, req).await?
+        }
+        None => return Err(HttpResponse::Forbidden().body("no account is linked to this identity")),
+    };
+
+    let result = entities::user_identity::ActiveModel {
+        user_id: Set(user.id),
+        issuer: Set(oidc.issuer.clone()),
+        subject: Set(claims.sub.clone()),
+        email: Set(claims.email.clone()),
+        created_at: Set(now.into()),
+        last_login_at: Set(now.into()),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await;
+
+    match result {
+        Ok(_) => (),
+        // a concurrent callback for the same identity got there first
+        Err(e) if is_unique_violation(&e) => return Err(HttpResponse::Conflict().body("identity is already being linked, try again")),
+        Err(e) => return Err(HttpResponse::InternalServerError().body(format!("could not link identity: {}", e))),
+    }
+
+    txn.commit().await.expect("could not commit transaction");
+
+    Ok(user)
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "auth",
+    responses(
+        (status = 302, description = "Redirect to the identity provider"),
+        (status = 404, description = "Single sign-on is not configured"),
+    ),
+)]
+#[get("/oidc/login")]
+async fn login(oidc: Option<web::Data<Oidc>>) -> impl Responder {
+
+    let oidc = match oidc {
+        Some(oidc) => oidc,
+        None => return HttpResponse::NotFound().body("single sign-on is not configured"),
+    };
+
+    let (url, pending) = oidc.begin();
+    let cookie = match encode_token(&pending) {
+        Ok(cookie) => cookie,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("could not start login: {}", e)),
+    };
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .cookie(login_cookie(cookie, &oidc))
+        .finish()
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "auth",
+    params(CallbackParams),
+    responses(
+        (status = 200, description = "An access token, or a `TwoFactorChallenge` when the account has two-factor enabled", body = String),
+        (status = 400, description = "Invalid or expired sign-in state"),
+        (status = 401, description = "The provider refused the sign-in, or the account is inactive"),
+        (status = 403, description = "No linked account and none may be created"),
+        (status = 404, description = "Single sign-on is not configured"),
+        (status = 409, description = "The identity is already being linked"),
+    ),
+)]
+#[get("/oidc/callback")]
+async fn callback(conn: web::Data<DatabaseConnection>, oidc: Option<web::Data<Oidc>>, params: web::Query<CallbackParams>, req: HttpRequest) -> HttpResponse {
+
+    let oidc = match oidc {
+        Some(oidc) => oidc,
+        None => return HttpResponse::NotFound().body("single sign-on is not configured"),
+    };
+
+    if let Some(error) = &params.error {
+        let description = params.error_description.as_deref().unwrap_or_default();
+        return HttpResponse::Unauthorized().body(format!("sign-in was not completed: {} {}", error, description));
+    }
+
+    let pending = req
+        .cookie(LOGIN_COOKIE)
+        .and_then(|cookie| decode_token::<PendingLogin>(cookie.value()).ok());
+
+    // the state has to match what this browser was sent off with
+    let (pending, code) = match (pending, &params.state, &params.code) {
+        (Some(pending), Some(state), Some(code)) if &pending.state == state => (pending, code),
+        _ => return HttpResponse::BadRequest().body("invalid or expired sign-in state"),
+    };
+
+    let claims = match oidc.finish(code, &pending).await {
+        Ok(claims) => claims,
+        Err(e) => return HttpResponse::Unauthorized().body(format!("could not sign in: {}", e)),
+    };
+
+    let user = match resolve_user(conn.as_ref(), &oidc, &claims, &req).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    let mut response = if !user.is_active {
+        HttpResponse::Unauthorized().body("You are unauthorized to use this route.")
+    } else if user.totp_enabled {
+        HttpResponse::Ok().json(TwoFactorChallenge {
+            two_factor_required: true,
+            token: create_pending_jwt(user.id, &user.email).unwrap(),
+        })
+    } else {
+        HttpResponse::Ok().json(create_jwt(user.id, &user.email, 1).unwrap())
+    };
+
+    // the state is single use
+    let mut expired = login_cookie(String::new(), &oidc);
+    expired.make_removal();
+    response.add_cookie(&expired).expect("could not clear login cookie");
+
+    response
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(login);
+    cfg.service(callback);
+}
@@ -1,263 +1,936 @@
-use actix_web::{web, HttpResponse, get, post, delete, patch, Responder, HttpRequest};
+use actix_web::{web, HttpResponse, get, post, put, delete, patch, Responder, HttpRequest, HttpMessage};
+use actix_web::http::header::{self, EntityTag, Header, IfMatch, IfNoneMatch, ETag};
 // use actix_web_httpauth::headers::authorization::Authorization;
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use chrono::Utc;
 
-use jsonwebtoken::{Algorithm, DecodingKey, decode, Validation};
-use jsonwebtoken::errors::Result as JwtResult;
+use async_graphql::InputObject;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
 
 use sea_orm::*;
 
 use entities::post::Entity as Post;
+use entities::user::Entity as User;
 use slugify::slugify;
 
-#[derive(Debug, Deserialize)]
+use super::users::PublicUser;
+use crate::audit::{self, Actor, Entry};
+use crate::auth::{authenticate, Identity, POSTS_DELETE, POSTS_WRITE};
+use crate::db::is_unique_violation;
+use crate::excerpt::{generate, summarize};
+use crate::payload::Payload;
+use crate::validation::{validate_slug, validation_error};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct Params {
     page: Option<u64>,
+    /// Between 1 and 100, 10 by default.
     posts_per_page: Option<u64>,
+    /// Comma-separated related data to embed, e.g. `author`.
+    include: Option<String>,
+    /// Comma-separated columns to return, e.g. `id,slug,title`.
+    fields: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    user_id: i32,
-    email: String,
-    exp: i64,
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IncludeParams {
+    /// Comma-separated related data to embed, e.g. `author`.
+    include: Option<String>,
 }
 
-fn validate_token(token: &str) -> JwtResult<Claims> {
-    let validation = Validation::new(Algorithm::HS256);
-    let secret = std::env::var("JWT_SECRET").unwrap_or("secret".to_string());
-    let key = DecodingKey::from_secret(secret.as_ref());
+/// What `include` accepts.
+const INCLUDES: &[&str] = &["author"];
 
-    let data = decode::<Claims>(token, &key, &validation)?;
-    Ok(data.claims)
+/// Related data to embed in post responses, from a comma-separated `include` parameter.
+/// Each one is loaded together with the posts, never with a query per post.
+#[derive(Debug, Default)]
+struct Includes {
+    /// The author's public profile, joined through `Relation::User`.
+    author: bool,
 }
 
-async fn check_is_valid(conn: &DatabaseConnection, user_id: i32, needs_admin: bool) -> bool {
-    let user = entities::user::Entity::find()
-        .filter(entities::user::Column::Id.eq(user_id))
-        .one(conn)
-        .await
-        .expect("could not find user");
+impl Includes {
+    /// Fails with a message naming the first unknown include.
+    fn parse(include: Option<&str>) -> Result<Self, String> {
+        let mut includes = Includes::default();
 
-    match user {
-        Some(user) => {
-            if !user.is_active {
-                return false;
+        for name in include.unwrap_or_default().split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "author" => includes.author = true,
+                other => return Err(format!("cannot include {}, expected any of: {}", other, INCLUDES.join(", "))),
             }
+        }
 
-            if needs_admin && !user.is_admin {
-                return false;
-            }
+        Ok(includes)
+    }
+}
 
-            return true;
+/// Columns `fields` may select. `text` is allowed but is what index pages usually leave out.
+const FIELDS: &[(&str, entities::post::Column)] = &[
+    ("id", entities::post::Column::Id),
+    ("user_id", entities::post::Column::UserId),
+    ("slug", entities::post::Column::Slug),
+    ("title", entities::post::Column::Title),
+    ("text", entities::post::Column::Text),
+    ("is_published", entities::post::Column::IsPublished),
+    ("excerpt", entities::post::Column::Excerpt),
+    ("word_count", entities::post::Column::WordCount),
+    ("reading_time", entities::post::Column::ReadingTime),
+    ("version", entities::post::Column::Version),
+];
+
+/// Parses a `fields` parameter into the columns to select, in the order given.
+fn parse_fields(fields: &str) -> Result<Vec<(&'static str, entities::post::Column)>, String> {
+    let mut columns = Vec::new();
+
+    for name in fields.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let field = FIELDS.iter().find(|(field, _)| *field == name).ok_or_else(|| {
+            let names: Vec<&str> = FIELDS.iter().map(|(field, _)| *field).collect();
+            format!("unknown field {}, expected any of: {}", name, names.join(", "))
+        })?;
+
+        if !columns.iter().any(|(selected, _)| selected == &field.0) {
+            columns.push(*field);
         }
-        None => return false,
     }
+
+    if columns.is_empty() {
+        return Err("fields must name at least one field".to_string());
+    }
+
+    Ok(columns)
 }
 
-#[get("/posts/")]
-async fn get_all(conn: web::Data<DatabaseConnection>, params: web::Query::<Params>) -> impl Responder {
+/// A post with its author embedded, from `include=author`.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct PostWithAuthor {
+    #[serde(flatten)]
+    #[schema(value_type = Post)]
+    post: entities::post::Model,
+    author: Option<PublicUser>,
+}
 
-    let page = params.page.unwrap_or(1);
-    let posts_per_page = params.posts_per_page.unwrap_or(10);
+impl From<(entities::post::Model, Option<entities::user::Model>)> for PostWithAuthor {
+    fn from((post, author): (entities::post::Model, Option<entities::user::Model>)) -> Self {
+        PostWithAuthor { post, author: author.as_ref().map(PublicUser::from) }
+    }
+}
 
+#[derive(Debug, Serialize, Deserialize, Validate, ToSchema, InputObject)]
+#[graphql(name = "PostInput")]
+pub struct PostForm {
+    #[validate(length(min = 1, max = 200, message = "title must be between 1 and 200 characters"))]
+    title: String,
+    #[validate(length(min = 1, max = 100000, message = "text must be between 1 and 100000 characters"))]
+    text: String,
+    #[validate(length(max = 100, message = "slug must be at most 100 characters"), custom = "validate_slug")]
+    slug: Option<String>,
+    /// Generated from the first paragraph of `text` when left out.
+    #[validate(length(max = 500, message = "excerpt must be at most 500 characters"))]
+    excerpt: Option<String>,
+    #[serde(default)]
+    #[graphql(default)]
+    is_published: bool,
+}
 
-    let paginator = Post::find()
-        .order_by_asc(entities::post::Column::Id)
-        .filter(entities::post::Column::IsPublished.eq(true))
-        .paginate(conn.as_ref(), posts_per_page);
+impl From<&entities::post::Model> for PostForm {
+    fn from(post: &entities::post::Model) -> Self {
+        PostForm {
+            title: post.title.clone(),
+            text: post.text.clone(),
+            slug: post.slug.clone(),
+            excerpt: post.excerpt.clone(),
+            is_published: post.is_published,
+        }
+    }
+}
 
-    let num_pages = paginator.num_pages().await.unwrap();
+/// A `PATCH` body, told apart by its `Content-Type`.
+enum PostPatch {
+    /// RFC 7396, also used for plain `application/json`.
+    Merge(serde_json::Value),
+    /// RFC 6902.
+    Json(json_patch::Patch),
+}
 
-    match paginator.fetch_page(page - 1).await {
-        Ok(posts) => {
-            return HttpResponse::Ok().json((posts, num_pages));
+impl PostPatch {
+    /// Applies the patch to the editable fields of a post. Fails if it touches anything
+    /// else or leaves something that isn't a post.
+    fn apply(&self, post: &PostForm) -> Result<PostForm, String> {
+        let original = serde_json::to_value(post).unwrap();
+        let mut patched = original.clone();
+
+        match self {
+            PostPatch::Merge(patch) => json_patch::merge(&mut patched, patch),
+            PostPatch::Json(patch) => json_patch::patch(&mut patched, &patch.0).map_err(|e| format!("could not apply patch: {}", e))?,
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("could not fetch posts: {}", e));
+
+        let fields = original.as_object().unwrap();
+        if let Some(unknown) = patched.as_object().and_then(|patched| patched.keys().find(|key| !fields.contains_key(*key))) {
+            return Err(format!("cannot patch {}, expected any of: {}", unknown, fields.keys().cloned().collect::<Vec<_>>().join(", ")));
         }
+
+        serde_json::from_value(patched).map_err(|e| format!("patched post is invalid: {}", e))
     }
 }
 
-#[get("/posts/{id}")]
-async fn get_by_id(conn: web::Data<DatabaseConnection>, id: web::Path<i32>) -> HttpResponse {
+/// Writes only the fields that differ between `original` and `patched`, leaving the
+/// rest `NotSet`. A removed slug is generated again from the title, and the excerpt is
+/// regenerated along with the text unless it was written by hand.
+async fn patch_post(conn: &DatabaseConnection, actor: &Actor, post: entities::post::Model, original: &PostForm, patched: &PostForm) -> Result<Option<entities::post::Model>, String> {
+    let mut changes = entities::post::ActiveModel { ..Default::default() };
+    let mut changed = false;
 
-    let post = Post::find()
-        .filter(entities::post::Column::Id.eq(id.clone()))
-        .one(conn.as_ref())
-        .await
-        .expect("could not find post");
+    if patched.title != original.title {
+        changes.title = Set(patched.title.clone());
+        changed = true;
+    }
+
+    let slug = if patched.slug != original.slug {
+        changed = true;
+        Some(SlugSource::from(patched))
+    } else {
+        None
+    };
+
+    if patched.is_published != original.is_published {
+        changes.is_published = Set(patched.is_published);
+        changed = true;
+    }
+
+    let text_changed = patched.text != original.text;
+    let excerpt_changed = patched.excerpt != original.excerpt;
+
+    if text_changed || excerpt_changed {
+        let was_generated = original.excerpt.as_deref() == Some(generate(&original.text).as_str());
+        let custom_excerpt = if excerpt_changed || !was_generated { patched.excerpt.as_deref() } else { None };
+
+        let (excerpt, word_count, reading_time) = summarize(&patched.text, custom_excerpt);
+
+        if Some(&excerpt) != original.excerpt.as_ref() {
+            changes.excerpt = Set(Some(excerpt));
+        }
+        if text_changed {
+            changes.text = Set(patched.text.clone());
+            changes.word_count = Set(word_count);
+            changes.reading_time = Set(reading_time);
+        }
+        changed = true;
+    }
 
-    match post {
-        Some(post) => HttpResponse::Ok().json(post),
-        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id.clone())),
+    if !changed {
+        return Ok(Some(post));
     }
+
+    save_post(conn, actor, Some(&post), changes, slug).await
 }
 
-#[get("/posts/{slug}")]
-async fn get_by_slug(conn: web::Data<DatabaseConnection>, slug: web::Path<String>) -> HttpResponse {
+/// Strong `ETag` of a post, which changes with every write.
+fn etag(post: &entities::post::Model) -> EntityTag {
+    EntityTag::new_strong(post.version.to_string())
+}
 
-    let post = Post::find()
-        .filter(entities::post::Column::Slug.eq(slug.clone()))
-        .one(conn.as_ref())
-        .await
-        .expect("could not find post");
+/// Set `REQUIRE_IF_MATCH=true` to refuse writes that don't say which version they change.
+pub(super) fn require_if_match() -> bool {
+    std::env::var("REQUIRE_IF_MATCH").ok().and_then(|v| v.parse().ok()).unwrap_or(false)
+}
 
-    match post {
-        Some(post) => HttpResponse::Ok().json(post),
-        None => return HttpResponse::NotFound().body(format!("post with slug: {} not found", slug.clone())),
+/// The response to send instead of writing to `post` when `If-Match` names another
+/// version of it, or is missing while `REQUIRE_IF_MATCH` is on.
+fn failed_precondition(req: &HttpRequest, post: &entities::post::Model) -> Option<HttpResponse> {
+    let current = etag(post);
+
+    match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => None,
+        Ok(IfMatch::Items(tags)) if tags.iter().any(|tag| tag.strong_eq(&current)) => None,
+        Ok(IfMatch::Items(tags)) if !tags.is_empty() => Some(
+            HttpResponse::PreconditionFailed()
+                .insert_header(ETag(current))
+                .body(format!("post with id: {} has changed since it was read", post.id)),
+        ),
+        Ok(_) if require_if_match() => Some(HttpResponse::PreconditionRequired().body("an If-Match header is required")),
+        Ok(_) => None,
+        Err(_) => Some(HttpResponse::BadRequest().body("invalid If-Match header")),
     }
 }
 
-#[post("/posts/")]
-async fn create(conn: web::Data<DatabaseConnection>, post_form: web::Form<entities::post::Model>, req: HttpRequest) -> impl Responder {
+/// Whether `If-None-Match` already names the current version of `post`.
+fn not_modified(req: &HttpRequest, post: &entities::post::Model) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag(post))),
+        Err(_) => false,
+    }
+}
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
+/// Where a saved post's slug comes from.
+enum SlugSource<'a> {
+    /// Generated from the title, with a `-2`, `-3`, … suffix if it's taken.
+    Title(&'a str),
+    /// Given by the client, who is told when it's taken.
+    Custom(&'a str),
+}
 
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+impl<'a> From<&'a PostForm> for SlugSource<'a> {
+    fn from(post_form: &'a PostForm) -> Self {
+        match &post_form.slug {
+            Some(slug) => SlugSource::Custom(slug),
+            None => SlugSource::Title(&post_form.title),
+        }
     }
+}
 
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
+/// Generated slugs are cut to this many characters before any suffix.
+const MAX_GENERATED_SLUG_LEN: usize = 60;
 
-    let user = match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
-    };
+/// How often a generated slug is retried when a concurrent write takes it first.
+const SLUG_ATTEMPTS: usize = 5;
 
+/// The slug a title starts out as, before it's made unique.
+fn slug_base(title: &str) -> String {
+    let slug = slugify!(title, max_length = MAX_GENERATED_SLUG_LEN);
+    let slug = slug.trim_end_matches('-');
 
-    match Post::find()
-        .filter(entities::post::Column::Slug.eq(slugify!(&post_form.title, max_length = 20)))
-        .one(conn.as_ref())
+    if slug.is_empty() {
+        "post".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Whether `slug` is `base` or `base` with a numeric suffix.
+fn is_slug_of(slug: &str, base: &str) -> bool {
+    match slug.strip_prefix(base) {
+        Some("") => true,
+        Some(rest) => rest.strip_prefix('-').is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+/// `base`, or `base-2`, `base-3`, … whichever is the first not used by another post.
+/// `post` keeps its own slug if that is already one of them.
+async fn free_slug(conn: &impl ConnectionTrait, base: &str, post: Option<&entities::post::Model>) -> Result<String, DbErr> {
+    if let Some(slug) = post.and_then(|post| post.slug.as_deref()).filter(|slug| is_slug_of(slug, base)) {
+        return Ok(slug.to_string());
+    }
+
+    let mut query = Post::find()
+        .select_only()
+        .column(entities::post::Column::Slug)
+        .filter(
+            Condition::any()
+                .add(entities::post::Column::Slug.eq(base))
+                .add(entities::post::Column::Slug.like(&format!("{}-%", base))),
+        );
+    if let Some(post) = post {
+        query = query.filter(entities::post::Column::Id.ne(post.id));
+    }
+
+    let taken: Vec<String> = query
+        .into_json()
+        .all(conn)
+        .await?
+        .iter()
+        .filter_map(|post| post["slug"].as_str().map(String::from))
+        .collect();
+
+    if !taken.iter().any(|slug| slug == base) {
+        return Ok(base.to_string());
+    }
+
+    let slug = (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|slug| !taken.contains(slug))
+        .unwrap();
+
+    Ok(slug)
+}
+
+/// A post that isn't in the trash.
+pub(super) async fn find_live(conn: &DatabaseConnection, id: i32) -> Option<entities::post::Model> {
+    Post::find_by_id(id)
+        .filter(entities::post::Column::DeletedAt.is_null())
+        .one(conn)
         .await
         .expect("could not find post")
-    {
-        Some(_) => return HttpResponse::BadRequest().body(format!("post with slug {} already exists", slugify!(&post_form.title, max_length = 20))),
-        None => (),
+}
+
+/// Saves `changes` to `post` only if nobody else saved it since it was read, bumping
+/// its version. Returns `None` when the stored version has moved on.
+async fn save_if_current(conn: &impl ConnectionTrait, post: &entities::post::Model, mut changes: entities::post::ActiveModel) -> Result<Option<entities::post::Model>, DbErr> {
+    changes.id = Set(post.id);
+    changes.version = Set(post.version + 1);
+
+    let result = Post::update(changes)
+        .filter(entities::post::Column::Version.eq(post.version))
+        .exec(conn)
+        .await;
+
+    match result {
+        Ok(post) => Ok(Some(post)),
+        Err(DbErr::RecordNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
     }
+}
 
-    entities::post::ActiveModel {
-        slug: Set({
-            if post_form.slug.is_none() {
-                Some(slugify!(&post_form.title, max_length = 20))
-            } else {
-                post_form.slug.clone()
+/// Inserts `changes` as a new post, or saves them to `post` if given, with a slug from
+/// `slug` unless it's `None`. The unique index on `slug` decides who gets it: a taken
+/// custom slug is an error, and a generated one moves on to the next free suffix. The
+/// audit log entry is written in the same transaction, so it's there if the change is.
+async fn save_post(
+    conn: &DatabaseConnection,
+    actor: &Actor,
+    post: Option<&entities::post::Model>,
+    changes: entities::post::ActiveModel,
+    slug: Option<SlugSource<'_>>,
+) -> Result<Option<entities::post::Model>, String> {
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    for _ in 0..SLUG_ATTEMPTS {
+        let mut changes = changes.clone();
+
+        match &slug {
+            Some(SlugSource::Title(title)) => {
+                let slug = free_slug(&txn, &slug_base(title), post).await.expect("could not find slugs");
+                changes.slug = Set(Some(slug));
+            }
+            Some(SlugSource::Custom(slug)) => changes.slug = Set(Some(slug.to_string())),
+            None => {}
+        }
+
+        // a failed statement aborts the transaction, so each attempt gets a savepoint
+        let attempt = txn.begin().await.expect("could not start savepoint");
+
+        let result = match post {
+            Some(post) => save_if_current(&attempt, post, changes).await,
+            None => changes.insert(&attempt).await.map(Some),
+        };
+
+        match result {
+            Err(e) if is_unique_violation(&e) => {
+                attempt.rollback().await.expect("could not roll back savepoint");
+
+                if let Some(SlugSource::Custom(slug)) = &slug {
+                    return Err(format!("post with slug {} already exists", slug));
+                }
+            }
+            result => {
+                let saved = result.expect("could not save post");
+                if let Some(saved) = &saved {
+                    audit::record(&attempt, actor, audit_entry(post, saved)).await;
+                }
+                attempt.commit().await.expect("could not release savepoint");
+                txn.commit().await.expect("could not commit transaction");
+                return Ok(saved);
             }
-        }),
+        }
+    }
+
+    Err("could not find a free slug, try again or choose one".to_string())
+}
+
+/// Answers a write with the saved post, `409` if its slug is taken, or `412` if another
+/// write got there first.
+fn saved(result: Result<Option<entities::post::Model>, String>, id: i32) -> HttpResponse {
+    match result {
+        Ok(Some(post)) => HttpResponse::Ok().insert_header(ETag(etag(&post))).json(post),
+        Ok(None) => HttpResponse::PreconditionFailed().body(format!("post with id: {} has changed since it was read", id)),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+/// Saves a new post by `actor`, failing with a message when its custom slug is taken.
+pub(super) async fn insert_post(conn: &DatabaseConnection, actor: &Actor, post_form: &PostForm) -> Result<entities::post::Model, String> {
+    let (excerpt, word_count, reading_time) = summarize(&post_form.text, post_form.excerpt.as_deref());
+
+    let post = entities::post::ActiveModel {
         title: Set(post_form.title.clone()),
         text: Set(post_form.text.clone()),
-        user_id: Set(Some(user.user_id.clone())),
-        is_published: Set(post_form.is_published.clone()),
+        user_id: Set(actor.user_id),
+        is_published: Set(post_form.is_published),
+        excerpt: Set(Some(excerpt)),
+        word_count: Set(word_count),
+        reading_time: Set(reading_time),
         ..Default::default()
-    }
-    .save(conn.as_ref())
-    .await
-    .expect("could not insert post");
+    };
+
+    let post = save_post(conn, actor, None, post, Some(SlugSource::from(post_form))).await?;
+
+    Ok(post.expect("inserted post is missing"))
+}
+
+/// Overwrites `post`, returning `None` if someone else saved it since it was read, and
+/// failing with a message when its custom slug is taken.
+pub(super) async fn update_post(conn: &DatabaseConnection, actor: &Actor, post: entities::post::Model, post_form: &PostForm) -> Result<Option<entities::post::Model>, String> {
+    let (excerpt, word_count, reading_time) = summarize(&post_form.text, post_form.excerpt.as_deref());
+
+    let changes = entities::post::ActiveModel {
+        title: Set(post_form.title.clone()),
+        text: Set(post_form.text.clone()),
+        is_published: Set(post_form.is_published),
+        excerpt: Set(Some(excerpt)),
+        word_count: Set(word_count),
+        reading_time: Set(reading_time),
+        ..Default::default()
+    };
 
-    HttpResponse::Ok().body("created post")
+    save_post(conn, actor, Some(&post), changes, Some(SlugSource::from(post_form))).await
 }
 
-#[patch("/posts/{id}")]
-async fn update(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, post_form: web::Form<entities::post::Model>, req: HttpRequest) -> impl Responder {
+/// Moves `post` to the trash, or returns `None` if someone else saved it since it was read.
+pub(super) async fn trash_post(conn: &DatabaseConnection, actor: &Actor, post: &entities::post::Model) -> Option<entities::post::Model> {
+    let changes = entities::post::ActiveModel { deleted_at: Set(Some(Utc::now().into())), ..Default::default() };
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
+    save_post(conn, actor, Some(post), changes, None).await.expect("could not trash post")
+}
+
+/// Takes `post` back out of the trash, or returns `None` if someone else saved it since
+/// it was read.
+async fn restore_post(conn: &DatabaseConnection, actor: &Actor, post: &entities::post::Model) -> Option<entities::post::Model> {
+    let changes = entities::post::ActiveModel { deleted_at: Set(None), ..Default::default() };
+
+    save_post(conn, actor, Some(post), changes, None).await.expect("could not restore post")
+}
+
+/// The audit log entry for saving `after` over `before`, or creating it if there's no
+/// `before`. Trashing, restoring and (un)publishing are called out.
+fn audit_entry(before: Option<&entities::post::Model>, after: &entities::post::Model) -> Entry {
+    let before = match before {
+        Some(before) => before,
+        None => return Entry::new("post.create", "post", after.id).after(after),
+    };
 
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+    let action = match (before.deleted_at.is_some(), after.deleted_at.is_some(), before.is_published, after.is_published) {
+        (false, true, _, _) => "post.delete",
+        (true, false, _, _) => "post.restore",
+        (_, _, false, true) => "post.publish",
+        (_, _, true, false) => "post.unpublish",
+        _ => "post.update",
+    };
+
+    Entry::new(action, "post", after.id).before(before).after(after)
+}
+
+/// Only a post's author and admins may trash or restore it.
+pub(super) fn can_trash(user: &Identity, post: &entities::post::Model) -> bool {
+    user.is_admin || post.user_id == Some(user.user_id)
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(Params),
+    responses(
+        (status = 200, description = "A page of published posts and the number of pages. With `fields`, posts only have the selected fields", body = PostPage),
+        (status = 400, description = "Unknown include or field"),
+    ),
+)]
+#[get("/")]
+async fn get_all(conn: web::Data<DatabaseConnection>, params: web::Query::<Params>) -> impl Responder {
+
+    let includes = match Includes::parse(params.include.as_deref()) {
+        Ok(includes) => includes,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let page = params.page.unwrap_or(1).max(1);
+    let posts_per_page = params.posts_per_page.unwrap_or(10).clamp(1, 100);
+
+    let query = Post::find()
+        .order_by_asc(entities::post::Column::Id)
+        .filter(entities::post::Column::IsPublished.eq(true))
+        .filter(entities::post::Column::DeletedAt.is_null());
+
+    if let Some(fields) = &params.fields {
+        let fields = match parse_fields(fields) {
+            Ok(fields) => fields,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        };
+
+        return get_all_sparse(conn.as_ref(), query, &fields, &includes, page, posts_per_page).await;
     }
 
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
+    if includes.author {
+        let paginator = query.find_also_related(User).paginate(conn.as_ref(), posts_per_page);
+
+        let num_pages = paginator.num_pages().await.unwrap();
+
+        return match paginator.fetch_page(page - 1).await {
+            Ok(posts) => HttpResponse::Ok().json((posts.into_iter().map(PostWithAuthor::from).collect::<Vec<_>>(), num_pages)),
+            Err(e) => HttpResponse::InternalServerError().body(format!("could not fetch posts: {}", e)),
+        };
+    }
+
+    let paginator = query.paginate(conn.as_ref(), posts_per_page);
+
+    let num_pages = paginator.num_pages().await.unwrap();
+
+    match paginator.fetch_page(page - 1).await {
+        Ok(posts) => HttpResponse::Ok().json((posts, num_pages)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not fetch posts: {}", e)),
+    }
+}
+
+/// Lists posts with only the selected columns, which are pushed down into the query.
+/// Authors are then loaded for the whole page in one more query.
+async fn get_all_sparse(
+    conn: &DatabaseConnection,
+    query: Select<Post>,
+    fields: &[(&'static str, entities::post::Column)],
+    includes: &Includes,
+    page: u64,
+    posts_per_page: u64,
+) -> HttpResponse {
+    let mut query = query.select_only();
+    for (_, column) in fields {
+        query = query.column(*column);
+    }
 
-    match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
+    // authors are matched up by `user_id`, so fetch it even when it wasn't asked for
+    let has_user_id = fields.iter().any(|(name, _)| *name == "user_id");
+    if includes.author && !has_user_id {
+        query = query.column(entities::post::Column::UserId);
+    }
+
+    let paginator = query.into_json().paginate(conn, posts_per_page);
+
+    let num_pages = paginator.num_pages().await.unwrap();
+
+    let mut posts = match paginator.fetch_page(page - 1).await {
+        Ok(posts) => posts,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("could not fetch posts: {}", e)),
+    };
+
+    if includes.author {
+        let user_ids: Vec<i64> = posts.iter().filter_map(|post| post["user_id"].as_i64()).collect();
+
+        let authors: HashMap<i32, PublicUser> = User::find()
+            .filter(entities::user::Column::Id.is_in(user_ids))
+            .all(conn)
+            .await
+            .expect("could not find users")
+            .iter()
+            .map(|user| (user.id, PublicUser::from(user)))
+            .collect();
+
+        for post in posts.iter_mut() {
+            let author = post["user_id"].as_i64().and_then(|id| authors.get(&(id as i32)));
+            post["author"] = serde_json::to_value(author).unwrap();
+
+            if !has_user_id {
+                post.as_object_mut().unwrap().remove("user_id");
             }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
+        }
+    }
+
+    HttpResponse::Ok().json((posts, num_pages))
+}
+
+/// Fetches a single post along with whatever `include` asks for.
+/// The `ETag` only follows the post, so it stays the same when an included author changes.
+/// Trashed posts aren't found.
+async fn find_post(conn: &DatabaseConnection, query: Select<Post>, params: &IncludeParams, req: &HttpRequest) -> Result<Option<HttpResponse>, HttpResponse> {
+    let includes = Includes::parse(params.include.as_deref()).map_err(|e| HttpResponse::BadRequest().body(e))?;
+    let query = query.filter(entities::post::Column::DeletedAt.is_null());
+
+    let (post, author) = if includes.author {
+        match query.find_also_related(User).one(conn).await.expect("could not find post") {
+            Some((post, author)) => (post, Some(author)),
+            None => return Ok(None),
+        }
+    } else {
+        match query.one(conn).await.expect("could not find post") {
+            Some(post) => (post, None),
+            None => return Ok(None),
+        }
     };
 
-    let post = Post::find()
-        .filter(entities::post::Column::Id.eq(id.clone()))
-        .one(conn.as_ref())
-        .await
-        .expect("could not find post");
-
-    match post {
-        Some(post) => {
-            let updated_post = entities::post::ActiveModel {
-                id: Set(post.id.clone()),
-                slug: Set({
-                    if post_form.slug.is_none() {
-                        Some(slugify!(&post_form.title, max_length = 20))
-                    } else {
-                        Some(slugify!(&post_form.slug.clone().unwrap(), max_length = 20))
-                    }
-                }),
-                title: Set(post_form.title.clone()),
-                text: Set(post_form.text.clone()),
-                is_published: Set(post_form.is_published.clone()),
-                ..Default::default()
-            };
-
-            return HttpResponse::Ok().json(updated_post.update(conn.as_ref()).await.expect("could not update post"));
+    if not_modified(req, &post) {
+        return Ok(Some(HttpResponse::NotModified().insert_header(ETag(etag(&post))).finish()));
+    }
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(ETag(etag(&post)));
+
+    Ok(Some(match author {
+        Some(author) => response.json(PostWithAuthor::from((post, author))),
+        None => response.json(post),
+    }))
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Id of the post"), IncludeParams, ("If-None-Match" = Option<String>, Header, description = "A previously seen `ETag`")),
+    responses(
+        (status = 200, description = "The post, with `author` only when included", body = PostWithAuthor, headers(("ETag" = String, description = "Version of the post"))),
+        (status = 304, description = "`If-None-Match` names the current version"),
+        (status = 400, description = "Unknown include"),
+        (status = 404, description = "No such post"),
+    ),
+)]
+#[get("/{id}")]
+async fn get_by_id(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, params: web::Query<IncludeParams>, req: HttpRequest) -> HttpResponse {
+
+    let query = Post::find().filter(entities::post::Column::Id.eq(*id));
+
+    match find_post(conn.as_ref(), query, &params, &req).await {
+        Ok(Some(response)) | Err(response) => response,
+        Ok(None) => HttpResponse::NotFound().body(format!("post with id: {} not found", id)),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("slug" = String, Path, description = "Slug of the post"), IncludeParams, ("If-None-Match" = Option<String>, Header, description = "A previously seen `ETag`")),
+    responses(
+        (status = 200, description = "The post, with `author` only when included", body = PostWithAuthor, headers(("ETag" = String, description = "Version of the post"))),
+        (status = 304, description = "`If-None-Match` names the current version"),
+        (status = 400, description = "Unknown include"),
+        (status = 404, description = "No such post"),
+    ),
+)]
+#[get("/slug/{slug}")]
+async fn get_by_slug(conn: web::Data<DatabaseConnection>, slug: web::Path<String>, params: web::Query<IncludeParams>, req: HttpRequest) -> HttpResponse {
+
+    let query = Post::find().filter(entities::post::Column::Slug.eq(slug.as_str()));
+
+    match find_post(conn.as_ref(), query, &params, &req).await {
+        Ok(Some(response)) | Err(response) => response,
+        Ok(None) => HttpResponse::NotFound().body(format!("post with slug: {} not found", slug)),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    request_body = PostForm,
+    responses(
+        (status = 201, description = "Post created", body = Post, headers(("Location" = String, description = "Where the new post can be fetched"))),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API key lacks the `posts:write` scope"),
+        (status = 409, description = "The custom slug is taken by another post"),
+        (status = 422, description = "Invalid post", body = ValidationErrorBody),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[post("/")]
+async fn create(conn: web::Data<DatabaseConnection>, post_form: Payload<PostForm>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, true, Some(POSTS_WRITE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = post_form.validate() {
+        return validation_error(e);
+    }
+
+    match insert_post(conn.as_ref(), &Actor::new(&req, Some(user.user_id)), &post_form).await {
+        Ok(post) => HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("/posts/{}", post.id)))
+            .insert_header(ETag(etag(&post)))
+            .json(post),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Id of the post"), ("If-Match" = Option<String>, Header, description = "The `ETag` being changed")),
+    request_body = PostForm,
+    responses(
+        (status = 200, description = "The replaced post", body = Post, headers(("ETag" = String, description = "New version of the post"))),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API key lacks the `posts:write` scope"),
+        (status = 404, description = "No such post"),
+        (status = 409, description = "The custom slug is taken by another post"),
+        (status = 412, description = "The post has changed since the `If-Match` version"),
+        (status = 422, description = "Invalid post", body = ValidationErrorBody),
+        (status = 428, description = "`If-Match` is missing and `REQUIRE_IF_MATCH` is on"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[put("/{id}")]
+async fn update(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, post_form: Payload<PostForm>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, true, Some(POSTS_WRITE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = post_form.validate() {
+        return validation_error(e);
+    }
+
+    let post = match find_live(conn.as_ref(), *id).await {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id)),
+    };
+
+    if let Some(response) = failed_precondition(&req, &post) {
+        return response;
+    }
+
+    saved(update_post(conn.as_ref(), &Actor::new(&req, Some(user.user_id)), post, &post_form).await, *id)
+}
+
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Id of the post"), ("If-Match" = Option<String>, Header, description = "The `ETag` being changed")),
+    request_body(
+        content = Object,
+        content_type = "application/merge-patch+json",
+        description = "A JSON Merge Patch of `PostForm` fields. A JSON Patch is accepted as `application/json-patch+json`",
+    ),
+    responses(
+        (status = 200, description = "The updated post", body = Post, headers(("ETag" = String, description = "New version of the post"))),
+        (status = 400, description = "The patch isn't valid JSON"),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API key lacks the `posts:write` scope"),
+        (status = 404, description = "No such post"),
+        (status = 409, description = "The custom slug is taken by another post"),
+        (status = 412, description = "The post has changed since the `If-Match` version"),
+        (status = 415, description = "Not a merge patch or JSON Patch"),
+        (status = 422, description = "The patch can't be applied, or leaves an invalid post", body = ValidationErrorBody),
+        (status = 428, description = "`If-Match` is missing and `REQUIRE_IF_MATCH` is on"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[patch("/{id}")]
+async fn partial_update(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, body: web::Bytes, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, true, Some(POSTS_WRITE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let patch = match req.content_type().to_lowercase().as_str() {
+        "application/merge-patch+json" | "application/json" => serde_json::from_slice(&body).map(PostPatch::Merge),
+        "application/json-patch+json" => serde_json::from_slice(&body).map(PostPatch::Json),
+        other => {
+            return HttpResponse::UnsupportedMediaType()
+                .body(format!("cannot patch with {}, expected application/merge-patch+json or application/json-patch+json", other))
         }
-        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id.clone())),
+    };
+
+    let patch = match patch {
+        Ok(patch) => patch,
+        Err(e) => return HttpResponse::BadRequest().body(format!("could not parse patch: {}", e)),
+    };
+
+    let post = match find_live(conn.as_ref(), *id).await {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id)),
+    };
+
+    if let Some(response) = failed_precondition(&req, &post) {
+        return response;
     }
+
+    let original = PostForm::from(&post);
+    let patched = match patch.apply(&original) {
+        Ok(patched) => patched,
+        Err(e) => return HttpResponse::UnprocessableEntity().body(e),
+    };
+
+    if let Err(e) = patched.validate() {
+        return validation_error(e);
+    }
+
+    saved(patch_post(conn.as_ref(), &Actor::new(&req, Some(user.user_id)), post, &original, &patched).await, *id)
 }
 
-#[delete("/posts/{id}")]
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Id of the post"), ("If-Match" = Option<String>, Header, description = "The `ETag` being changed")),
+    responses(
+        (status = 200, description = "Post moved to the trash", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not signed in, or neither the author nor an admin"),
+        (status = 403, description = "API key lacks the `posts:delete` scope"),
+        (status = 404, description = "No such post, or it's already in the trash"),
+        (status = 412, description = "The post has changed since the `If-Match` version"),
+        (status = 428, description = "`If-Match` is missing and `REQUIRE_IF_MATCH` is on"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[delete("/{id}")]
 async fn delete(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
+    let user = match authenticate(conn.as_ref(), &req, false, Some(POSTS_DELETE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let post = match find_live(conn.as_ref(), *id).await {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id)),
+    };
 
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+    if !can_trash(&user, &post) {
+        return HttpResponse::Unauthorized().body("user is not authorized to delete this post");
     }
 
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
+    if let Some(response) = failed_precondition(&req, &post) {
+        return response;
+    }
 
-    let user = match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
-    };    
-
-    let found_post = Post::find()
-        .filter(entities::post::Column::Id.eq(id.clone()))
-        .one(conn.as_ref())
-        .await
-        .expect("could not find post");
+    match trash_post(conn.as_ref(), &Actor::new(&req, Some(user.user_id)), &post).await {
+        Some(_) => HttpResponse::Ok().body(format!("Deleted post: {}", id)),
+        None => HttpResponse::PreconditionFailed().body(format!("post with id: {} has changed since it was read", id)),
+    }
+}
 
+#[utoipa::path(
+    context_path = "/posts",
+    tag = "posts",
+    params(("id" = i32, Path, description = "Id of the post"), ("If-Match" = Option<String>, Header, description = "The `ETag` being changed")),
+    responses(
+        (status = 200, description = "The restored post", body = Post, headers(("ETag" = String, description = "New version of the post"))),
+        (status = 401, description = "Not signed in, or neither the author nor an admin"),
+        (status = 403, description = "API key lacks the `posts:delete` scope"),
+        (status = 404, description = "No such post"),
+        (status = 409, description = "The post isn't in the trash"),
+        (status = 412, description = "The post has changed since the `If-Match` version"),
+        (status = 428, description = "`If-Match` is missing and `REQUIRE_IF_MATCH` is on"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[post("/{id}/restore")]
+async fn restore(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, false, Some(POSTS_DELETE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
 
-    match found_post {
-        Some(post) => {
+    let post = match Post::find_by_id(*id).one(conn.as_ref()).await.expect("could not find post") {
+        Some(post) => post,
+        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id)),
+    };
 
-            if post.user_id.unwrap() != user.user_id {
-                return HttpResponse::Unauthorized().body("user is not authorized to delete this post");
-            }
+    if !can_trash(&user, &post) {
+        return HttpResponse::Unauthorized().body("user is not authorized to restore this post");
+    }
 
-            entities::post::ActiveModel {
-                id: Set(id.clone()),
-                ..Default::default()
-            };
-        
-            post.delete(conn.as_ref()).await.expect("could not delete post");
+    if post.deleted_at.is_none() {
+        return HttpResponse::Conflict().body(format!("post with id: {} is not in the trash", id));
+    }
 
-            return HttpResponse::Ok().body(format!("Deleted post: {}", id.clone()));
-        }
-        None => return HttpResponse::NotFound().body(format!("post with id: {} not found", id.clone())),
+    if let Some(response) = failed_precondition(&req, &post) {
+        return response;
     }
+
+    saved(Ok(restore_post(conn.as_ref(), &Actor::new(&req, Some(user.user_id)), &post).await), *id)
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
@@ -266,5 +939,35 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(get_by_slug);
     cfg.service(create);
     cfg.service(update);
+    cfg.service(partial_update);
+    cfg.service(restore);
     cfg.service(delete);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use super::*;
+
+    /// An unknown `include` is refused before the database is touched, so a `400` shows
+    /// which handler the router picked without needing a database.
+    #[actix_web::test]
+    async fn slug_lookups_reach_get_by_slug() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(DatabaseConnection::Disconnected))
+                .service(web::scope("/posts").configure(init_routes)),
+        )
+        .await;
+
+        for uri in ["/posts/1?include=tags", "/posts/slug/hello-world?include=tags"] {
+            let response = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            let status = response.status();
+            let body = test::read_body(response).await;
+
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{} answered {:?}", uri, body);
+            assert!(String::from_utf8_lossy(&body).contains("tags"), "{} answered {:?}", uri, body);
+        }
+    }
+}
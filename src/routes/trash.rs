@@ -0,0 +1,62 @@
+use actix_web::{web, HttpResponse, get, Responder, HttpRequest};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use sea_orm::*;
+
+use entities::post::Entity as Post;
+
+use crate::auth::{authenticate, POSTS_DELETE};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Params {
+    page: Option<u64>,
+    /// Between 1 and 100, 10 by default.
+    posts_per_page: Option<u64>,
+}
+
+#[utoipa::path(
+    context_path = "/trash",
+    tag = "posts",
+    params(Params),
+    responses(
+        (status = 200, description = "A page of trashed posts, most recently deleted first, and the number of pages. Admins see every trashed post, others only their own", body = TrashPage),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "API key lacks the `posts:delete` scope"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[get("")]
+async fn get_all(conn: web::Data<DatabaseConnection>, params: web::Query<Params>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticate(conn.as_ref(), &req, false, Some(POSTS_DELETE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    let page = params.page.unwrap_or(1).max(1);
+    let posts_per_page = params.posts_per_page.unwrap_or(10).clamp(1, 100);
+
+    let mut query = Post::find()
+        .filter(entities::post::Column::DeletedAt.is_not_null())
+        .order_by_desc(entities::post::Column::DeletedAt)
+        .order_by_asc(entities::post::Column::Id);
+
+    if !user.is_admin {
+        query = query.filter(entities::post::Column::UserId.eq(user.user_id));
+    }
+
+    let paginator = query.paginate(conn.as_ref(), posts_per_page);
+
+    let num_pages = paginator.num_pages().await.expect("could not count posts");
+
+    match paginator.fetch_page(page - 1).await {
+        Ok(posts) => HttpResponse::Ok().json((posts, num_pages)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not fetch posts: {}", e)),
+    }
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_all);
+}
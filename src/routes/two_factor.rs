@@ -0,0 +1,318 @@
+use actix_web::{web, HttpResponse, get, post, delete, Responder, HttpRequest};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use sea_orm::*;
+use sea_orm::sea_query::Expr;
+
+use entities::recovery_code::Entity as RecoveryCode;
+use entities::user::Entity as User;
+
+use crate::audit::{self, Actor, Entry};
+use crate::auth::{authenticate, create_jwt, validate_pending_token};
+use crate::lockout::{too_many_attempts, LoginGuard};
+use crate::payload::Payload;
+use crate::two_factor::{self, check_code, hash_recovery_code};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CodeForm {
+    code: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ChallengeForm {
+    /// The pending token returned by `login`.
+    token: String,
+    /// A current TOTP code or an unused recovery code.
+    code: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Resolves the caller to an active user. API keys can't manage two-factor.
+async fn authenticated_user(conn: &DatabaseConnection, req: &HttpRequest) -> Result<entities::user::Model, HttpResponse> {
+    let identity = authenticate(conn, req, false, None).await?;
+
+    User::find_by_id(identity.user_id)
+        .one(conn)
+        .await
+        .expect("could not find user")
+        .ok_or_else(|| HttpResponse::Unauthorized().body("You are unauthorized to use this route."))
+}
+
+/// Accepts a current TOTP code once: its time step has to be later than the last one
+/// accepted for the user, and is stored in the same conditional update.
+async fn use_totp_code(conn: &DatabaseConnection, user: &entities::user::Model, code: &str) -> Result<bool, DbErr> {
+    let step = match user.totp_secret.as_deref().and_then(|secret| check_code(secret, &user.username, code)) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    let result = User::update_many()
+        .col_expr(entities::user::Column::TotpLastStep, Expr::value(step))
+        .filter(entities::user::Column::Id.eq(user.id))
+        .filter(
+            Condition::any()
+                .add(entities::user::Column::TotpLastStep.is_null())
+                .add(entities::user::Column::TotpLastStep.lt(step)),
+        )
+        .exec(conn)
+        .await?;
+
+    Ok(result.rows_affected > 0)
+}
+
+/// Accepts either a current, unused TOTP code or an unused recovery code, burning either.
+async fn verify_second_factor(conn: &DatabaseConnection, user: &entities::user::Model, code: &str) -> Result<bool, DbErr> {
+    if use_totp_code(conn, user, code).await? {
+        return Ok(true);
+    }
+
+    // a single conditional update, so a code can't be spent twice concurrently
+    let result = RecoveryCode::update_many()
+        .col_expr(entities::recovery_code::Column::UsedAt, Expr::value(Utc::now()))
+        .filter(entities::recovery_code::Column::UserId.eq(user.id))
+        .filter(entities::recovery_code::Column::CodeHash.eq(hash_recovery_code(code)))
+        .filter(entities::recovery_code::Column::UsedAt.is_null())
+        .exec(conn)
+        .await?;
+
+    Ok(result.rows_affected > 0)
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "two-factor",
+    responses(
+        (status = 200, description = "A new secret, pending activation", body = Enrollment),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 409, description = "Two-factor is already enabled"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/me/2fa")]
+async fn enroll(conn: web::Data<DatabaseConnection>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticated_user(conn.as_ref(), &req).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.totp_enabled {
+        return HttpResponse::Conflict().body("two-factor authentication is already enabled");
+    }
+
+    let secret = two_factor::generate_secret();
+    let otpauth_uri = match two_factor::totp(&secret, &user.username) {
+        Ok(totp) => totp.get_url(),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("could not create authenticator: {}", e)),
+    };
+
+    let mut user: entities::user::ActiveModel = user.into();
+    user.totp_secret = Set(Some(secret.clone()));
+    user.totp_last_step = Set(None);
+    user.update(conn.as_ref()).await.expect("could not update user");
+
+    HttpResponse::Ok().json(Enrollment { secret, otpauth_uri })
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "two-factor",
+    responses(
+        (status = 200, description = "QR code of the pending secret", content_type = "image/png"),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 404, description = "No pending enrollment"),
+        (status = 409, description = "Two-factor is already enabled"),
+    ),
+    security(("bearer" = [])),
+)]
+#[get("/me/2fa/qr.png")]
+async fn qr_code(conn: web::Data<DatabaseConnection>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticated_user(conn.as_ref(), &req).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    // the secret is only shown while enrollment is pending
+    let secret = match (&user.totp_secret, user.totp_enabled) {
+        (Some(secret), false) => secret,
+        (_, true) => return HttpResponse::Conflict().body("two-factor authentication is already enabled"),
+        (None, false) => return HttpResponse::NotFound().body("no pending two-factor enrollment"),
+    };
+
+    let png = two_factor::totp(secret, &user.username).and_then(|totp| two_factor::qr_png(&totp.get_url()));
+
+    match png {
+        Ok(png) => HttpResponse::Ok().content_type("image/png").body(png),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not render qr code: {}", e)),
+    }
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "two-factor",
+    request_body = CodeForm,
+    responses(
+        (status = 200, description = "Two-factor enabled. The recovery codes are only shown once", body = RecoveryCodes),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 404, description = "No pending enrollment"),
+        (status = 409, description = "Two-factor is already enabled"),
+        (status = 422, description = "Invalid code"),
+    ),
+    security(("bearer" = [])),
+)]
+#[post("/me/2fa/activate")]
+async fn activate(conn: web::Data<DatabaseConnection>, code_form: Payload<CodeForm>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticated_user(conn.as_ref(), &req).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.totp_enabled {
+        return HttpResponse::Conflict().body("two-factor authentication is already enabled");
+    }
+
+    if user.totp_secret.is_none() {
+        return HttpResponse::NotFound().body("no pending two-factor enrollment");
+    }
+
+    if !use_totp_code(conn.as_ref(), &user, &code_form.code).await.expect("could not verify code") {
+        return HttpResponse::UnprocessableEntity().body("invalid two-factor code");
+    }
+
+    let recovery_codes = two_factor::generate_recovery_codes();
+
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    RecoveryCode::delete_many()
+        .filter(entities::recovery_code::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await
+        .expect("could not delete recovery codes");
+
+    RecoveryCode::insert_many(recovery_codes.iter().map(|code| entities::recovery_code::ActiveModel {
+        user_id: Set(user.id),
+        code_hash: Set(hash_recovery_code(code)),
+        ..Default::default()
+    }))
+    .exec(&txn)
+    .await
+    .expect("could not insert recovery codes");
+
+    let user_id = user.id;
+    let mut user: entities::user::ActiveModel = user.into();
+    user.totp_enabled = Set(true);
+    user.update(&txn).await.expect("could not update user");
+
+    audit::record(&txn, &Actor::new(&req, Some(user_id)), Entry::new("user.enable_two_factor", "user", user_id)).await;
+    txn.commit().await.expect("could not commit transaction");
+
+    HttpResponse::Ok().json(RecoveryCodes { recovery_codes })
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "two-factor",
+    request_body = CodeForm,
+    responses(
+        (status = 200, description = "Two-factor disabled", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not signed in with a token"),
+        (status = 422, description = "Invalid code"),
+    ),
+    security(("bearer" = [])),
+)]
+#[delete("/me/2fa")]
+async fn disable(conn: web::Data<DatabaseConnection>, code_form: Payload<CodeForm>, req: HttpRequest) -> impl Responder {
+
+    let user = match authenticated_user(conn.as_ref(), &req).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    if user.totp_enabled && !verify_second_factor(conn.as_ref(), &user, &code_form.code).await.expect("could not verify code") {
+        return HttpResponse::UnprocessableEntity().body("invalid two-factor code");
+    }
+
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    RecoveryCode::delete_many()
+        .filter(entities::recovery_code::Column::UserId.eq(user.id))
+        .exec(&txn)
+        .await
+        .expect("could not delete recovery codes");
+
+    let user_id = user.id;
+    let mut user: entities::user::ActiveModel = user.into();
+    user.totp_secret = Set(None);
+    user.totp_enabled = Set(false);
+    user.totp_last_step = Set(None);
+    user.update(&txn).await.expect("could not update user");
+
+    audit::record(&txn, &Actor::new(&req, Some(user_id)), Entry::new("user.disable_two_factor", "user", user_id)).await;
+    txn.commit().await.expect("could not commit transaction");
+
+    HttpResponse::Ok().body("two-factor authentication disabled")
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "auth",
+    request_body = ChallengeForm,
+    responses(
+        (status = 200, description = "An access token", body = String),
+        (status = 401, description = "Invalid or expired challenge, or wrong code"),
+        (status = 429, description = "Too many failed attempts, see `Retry-After`"),
+    ),
+)]
+#[post("/login/2fa")]
+async fn challenge(conn: web::Data<DatabaseConnection>, guard: web::Data<LoginGuard>, challenge_form: Payload<ChallengeForm>) -> HttpResponse {
+
+    let claims = match validate_pending_token(&challenge_form.token) {
+        Ok(claims) => claims,
+        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
+    };
+
+    // codes are only six digits, so failures count towards the same lockout as passwords
+    let account_key = LoginGuard::account_key(claims.user_id);
+
+    if let Some(wait) = guard.retry_after(&account_key).await.expect("could not check login attempts") {
+        return too_many_attempts(wait);
+    }
+
+    let user = match User::find_by_id(claims.user_id).one(conn.as_ref()).await.expect("could not find user") {
+        Some(user) if user.totp_enabled => user,
+        _ => return HttpResponse::Unauthorized().body("invalid two-factor code"),
+    };
+
+    if !verify_second_factor(conn.as_ref(), &user, &challenge_form.code).await.expect("could not verify code") {
+        return match guard.record_account_failure(&account_key).await {
+            Ok(()) => HttpResponse::Unauthorized().body("invalid two-factor code"),
+            Err(e) => HttpResponse::InternalServerError().body(format!("could not record login attempt: {}", e)),
+        };
+    }
+
+    guard.clear(&account_key).await.expect("could not clear login attempts");
+
+    HttpResponse::Ok().json(create_jwt(user.id, &user.email, 1).unwrap())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(enroll);
+    cfg.service(qr_code);
+    cfg.service(activate);
+    cfg.service(disable);
+    cfg.service(challenge);
+}
@@ -1,271 +1,642 @@
 use actix_web::{web, HttpResponse, get, post, delete, put, Responder, HttpRequest};
+use actix_web::http::header;
 
-use bcrypt::{hash, verify, DEFAULT_COST};
-
-use jsonwebtoken::{Algorithm, EncodingKey, DecodingKey, decode, Validation, Header};
-use jsonwebtoken::errors::Result as JwtResult;
-use chrono::{Utc, Duration};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
 
 use sea_orm::DatabaseConnection;
 use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::Validate;
 
 use sea_orm::*;
+use sea_orm::prelude::Json;
+use sea_orm::sea_query::{Expr, Func};
 
 use entities::user::Entity as User;
 
 use slugify::slugify;
 
-#[derive(Debug, Deserialize)]
+use crate::audit::{self, Actor, Entry};
+use crate::auth::{authenticate, create_jwt, create_pending_jwt, Identity, USERS_READ, USERS_WRITE};
+use crate::client_ip::client_ip;
+use crate::db::is_unique_violation;
+use crate::lockout::{too_many_attempts, LoginGuard};
+use crate::passwords::{check_password, hash_password, needs_rehash};
+use crate::payload::Payload;
+use crate::validation::{validate_http_url, validate_password_strength, validate_social_links, validate_username, validation_error};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct Params {
     page: Option<u64>,
+    /// Between 1 and 100, 10 by default.
     users_per_page: Option<u64>,
+    /// Matches anywhere in the username or email, case-insensitively.
+    q: Option<String>,
+    is_active: Option<bool>,
+    is_admin: Option<bool>,
+    /// `id`, `username`, `email` or `post_count`, prefixed with `-` for descending.
+    sort: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct LoginForm {
-    username: String,
+    /// Either the username or the email address.
+    #[serde(alias = "username", alias = "email")]
+    login: String,
     password: String,
     keep_logged_in: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    user_id: i32,
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct UserForm {
+    #[validate(length(min = 3, max = 32, message = "username must be between 3 and 32 characters"), custom = "validate_username")]
+    username: String,
+    #[validate(email(message = "email is not a valid address"), length(max = 254, message = "email must be at most 254 characters"))]
     email: String,
-    exp: i64,
+    #[validate(length(min = 8, max = 128, message = "password must be between 8 and 128 characters"), custom = "validate_password_strength")]
+    password: String,
 }
 
-fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
-    hash(password, DEFAULT_COST)
+/// Replaces the caller's profile. Missing or empty fields are cleared.
+#[derive(Debug, Deserialize, Validate, ToSchema)]
+pub struct ProfileForm {
+    #[validate(length(max = 64, message = "display name must be at most 64 characters"))]
+    display_name: Option<String>,
+    #[validate(length(max = 2000, message = "bio must be at most 2000 characters"))]
+    bio: Option<String>,
+    #[validate(length(max = 2048, message = "website must be at most 2048 characters"), custom = "validate_http_url")]
+    website: Option<String>,
+    #[validate(length(max = 2048, message = "avatar url must be at most 2048 characters"), custom = "validate_http_url")]
+    avatar_url: Option<String>,
+    #[validate(custom = "validate_social_links")]
+    social_links: Option<BTreeMap<String, String>>,
 }
 
-fn check_password(password: &str, hashed_password: &str) -> Result<bool, bcrypt::BcryptError> {
-    verify(password, hashed_password)
+/// Returned by `login` instead of a token when the account has two-factor enabled.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct TwoFactorChallenge {
+    pub(super) two_factor_required: bool,
+    /// Exchanged together with a code at `POST /users/login/2fa`.
+    pub(super) token: String,
 }
 
-fn create_jwt(user_id: i32, email: &str, days: i64) -> JwtResult<String> {
-    let header = Header::new(Algorithm::HS256);
+/// A user as anyone may see them.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct PublicUser {
+    id: i32,
+    username: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    website: Option<String>,
+    avatar_url: Option<String>,
+    /// Links keyed by network name.
+    #[schema(value_type = Option<Object>)]
+    social_links: Option<Json>,
+}
 
-    let expiration_date = Utc::now() + Duration::days(days);
+impl From<&entities::user::Model> for PublicUser {
+    fn from(user: &entities::user::Model) -> Self {
+        PublicUser {
+            id: user.id,
+            username: user.username.clone(),
+            display_name: user.display_name.clone(),
+            bio: user.bio.clone(),
+            website: user.website.clone(),
+            avatar_url: user.avatar_url.clone(),
+            social_links: user.social_links.clone(),
+        }
+    }
+}
 
+/// A user as shown to themselves and to admins.
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct PrivateUser {
+    #[serde(flatten)]
+    profile: PublicUser,
+    email: String,
+    is_active: bool,
+    is_admin: bool,
+    totp_enabled: bool,
+}
 
-    let claims = Claims {
-        user_id,
-        email: email.to_string(),
-        exp: expiration_date.timestamp(),
-    };
+impl From<&entities::user::Model> for PrivateUser {
+    fn from(user: &entities::user::Model) -> Self {
+        PrivateUser {
+            profile: user.into(),
+            email: user.email.clone(),
+            is_active: user.is_active,
+            is_admin: user.is_admin,
+            totp_enabled: user.totp_enabled,
+        }
+    }
+}
 
-    let secret = std::env::var("JWT_SECRET").unwrap_or("secret".to_string());
-    let key = EncodingKey::from_secret(secret.as_ref());
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub(super) enum UserView {
+    Public(PublicUser),
+    Private(PrivateUser),
+}
 
-    jsonwebtoken::encode(&header, &claims, &key)
+impl UserView {
+    /// Picks what `viewer` is allowed to see of `user`.
+    fn for_viewer(user: &entities::user::Model, viewer: &Identity) -> Self {
+        if viewer.is_admin || viewer.user_id == user.id {
+            UserView::Private(user.into())
+        } else {
+            UserView::Public(user.into())
+        }
+    }
 }
 
-fn validate_token(token: &str) -> JwtResult<Claims> {
-    let validation = Validation::new(Algorithm::HS256);
-    let secret = std::env::var("JWT_SECRET").unwrap_or("secret".to_string());
-    let key = DecodingKey::from_secret(secret.as_ref());
+/// A row of the admin user directory.
+#[derive(Debug, FromQueryResult)]
+struct UserRow {
+    id: i32,
+    username: String,
+    email: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    website: Option<String>,
+    avatar_url: Option<String>,
+    social_links: Option<Json>,
+    is_active: bool,
+    is_admin: bool,
+    totp_enabled: bool,
+    post_count: i64,
+}
 
-    let data = decode::<Claims>(token, &key, &validation)?;
-    Ok(data.claims)
+#[derive(Debug, Serialize, ToSchema)]
+pub(super) struct ListedUser {
+    #[serde(flatten)]
+    user: PrivateUser,
+    post_count: i64,
 }
 
-async fn check_is_valid(conn: &DatabaseConnection, user_id: i32, needs_admin: bool) -> bool {
-    let user = entities::user::Entity::find()
-        .filter(entities::user::Column::Id.eq(user_id))
-        .one(conn)
-        .await
-        .expect("could not find user");
+impl From<UserRow> for ListedUser {
+    fn from(row: UserRow) -> Self {
+        ListedUser {
+            user: PrivateUser {
+                profile: PublicUser {
+                    id: row.id,
+                    username: row.username,
+                    display_name: row.display_name,
+                    bio: row.bio,
+                    website: row.website,
+                    avatar_url: row.avatar_url,
+                    social_links: row.social_links,
+                },
+                email: row.email,
+                is_active: row.is_active,
+                is_admin: row.is_admin,
+                totp_enabled: row.totp_enabled,
+            },
+            post_count: row.post_count,
+        }
+    }
+}
 
-    match user {
-        Some(user) => {
-            if !user.is_active {
-                return false;
-            }
+/// Escapes `LIKE` wildcards so search terms match literally.
+fn like_pattern(term: &str) -> String {
+    let escaped = term.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
 
-            if needs_admin && !user.is_admin {
-                return false;
-            }
+/// Trims a profile field, treating blank as unset.
+fn non_blank(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty()).map(str::to_owned)
+}
 
-            return true;
-        }
-        None => return false,
-    }
+/// Emails are compared and stored trimmed and lowercased.
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
 }
 
-#[get("/users/")]
-async fn get_all(conn: web::Data<DatabaseConnection>, params: web::Query::<Params>, req: HttpRequest) -> impl Responder {
+/// Hash checked against when the login matches no user, so unknown accounts
+/// take as long to reject as wrong passwords.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not-a-real-password").unwrap())
+}
+
+/// Set `PASSWORD_LOGIN=false` to only allow single sign-on.
+fn password_login_enabled() -> bool {
+    std::env::var("PASSWORD_LOGIN").ok().and_then(|v| v.parse().ok()).unwrap_or(true)
+}
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    params(Params),
+    responses(
+        (status = 200, description = "A page of users with their post counts, and the number of pages", body = UserPage),
+        (status = 400, description = "Unknown sort column"),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API key lacks the `users:read` scope"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[get("/")]
+async fn get_all(conn: web::Data<DatabaseConnection>, params: web::Query::<Params>, req: HttpRequest) -> impl Responder {
 
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+    if let Err(response) = authenticate(conn.as_ref(), &req, true, Some(USERS_READ)).await {
+        return response;
     }
 
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
+    let page = params.page.unwrap_or(1).max(1);
+    let users_per_page = params.users_per_page.unwrap_or(10).clamp(1, 100);
 
-    match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
+    let post_count = entities::post::Column::Id.count();
+
+    let sort = params.sort.as_deref().unwrap_or("id");
+    let (column, order) = match sort.strip_prefix('-') {
+        Some(column) => (column, Order::Desc),
+        None => (sort, Order::Asc),
     };
 
-    let page = params.page.unwrap_or(1);
-    let users_per_page = params.users_per_page.unwrap_or(10);
+    let sort_by = match column {
+        "id" => Expr::col((entities::user::Entity, entities::user::Column::Id)).into(),
+        "username" => Expr::col((entities::user::Entity, entities::user::Column::Username)).into(),
+        "email" => Expr::col((entities::user::Entity, entities::user::Column::Email)).into(),
+        "post_count" => post_count.clone(),
+        _ => return HttpResponse::BadRequest().body("sort must be one of: id, username, email, post_count"),
+    };
 
+    let mut query = User::find()
+        // trashed posts don't count
+        .join(
+            JoinType::LeftJoin,
+            entities::user::Relation::Post
+                .def()
+                .on_condition(|_, post| Condition::all().add(Expr::col((post, entities::post::Column::DeletedAt)).is_null())),
+        )
+        .column_as(post_count, "post_count")
+        .group_by(entities::user::Column::Id)
+        .order_by(sort_by, order)
+        // keeps pages stable when sorting by a column with ties
+        .order_by_asc(entities::user::Column::Id);
+
+    if let Some(q) = params.q.as_deref().map(str::trim).filter(|q| !q.is_empty()) {
+        let pattern = like_pattern(q);
+        query = query.filter(
+            Condition::any()
+                .add(Expr::expr(Func::lower(Expr::col((entities::user::Entity, entities::user::Column::Username)))).like(pattern.as_str()))
+                .add(Expr::expr(Func::lower(Expr::col((entities::user::Entity, entities::user::Column::Email)))).like(pattern.as_str())),
+        );
+    }
 
-    let paginator = User::find()
-        .order_by_asc(entities::post::Column::Id)
-        .filter(entities::post::Column::IsPublished.eq(true))
+    if let Some(is_active) = params.is_active {
+        query = query.filter(entities::user::Column::IsActive.eq(is_active));
+    }
+
+    if let Some(is_admin) = params.is_admin {
+        query = query.filter(entities::user::Column::IsAdmin.eq(is_admin));
+    }
+
+    let paginator = query
+        .into_model::<UserRow>()
         .paginate(conn.as_ref(), users_per_page);
 
-    let num_pages = paginator.num_pages().await.unwrap();
+    let num_pages = match paginator.num_pages().await {
+        Ok(num_pages) => num_pages,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("could not fetch users: {}", e)),
+    };
 
     match paginator.fetch_page(page - 1).await {
-        Ok(users) => {
-            return HttpResponse::Ok().json((users, num_pages));
-        }
-        Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("could not fetch users: {}", e));
-        }
+        Ok(users) => HttpResponse::Ok().json((users.into_iter().map(ListedUser::from).collect::<Vec<_>>(), num_pages)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not fetch users: {}", e)),
     }
 }
 
-#[get("/users/{id}")]
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    params(("id" = i32, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "The user. Email and account flags are only shown to the user and to admins", body = UserView),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "API key lacks the `users:read` scope"),
+        (status = 404, description = "No such user"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[get("/{id}")]
 async fn get_by_id(conn: web::Data<DatabaseConnection>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
-
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
-    }
-
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
-
-    match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
+    let viewer = match authenticate(conn.as_ref(), &req, false, Some(USERS_READ)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
     };
 
     let user = User::find()
-        .filter(entities::user::Column::Id.eq(id.clone()))
+        .filter(entities::user::Column::Id.eq(*id))
         .one(conn.as_ref())
         .await
-        .expect("could not find post");
+        .expect("could not find user");
 
     match user {
-        Some(user) => HttpResponse::Ok().json(user),
-        None => return HttpResponse::NotFound().body(format!("user with id: {} not found", id.clone())),
+        Some(user) => HttpResponse::Ok().json(UserView::for_viewer(&user, &viewer)),
+        None => HttpResponse::NotFound().body(format!("user with id: {} not found", id)),
     }
 }
 
-#[post("/users/")]
-async fn create(conn: web::Data<DatabaseConnection>, user_form: web::Form<entities::user::Model>) -> impl Responder {
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    request_body = UserForm,
+    responses(
+        (status = 201, description = "User registered", body = PrivateUser, headers(("Location" = String, description = "Where the new user can be fetched"))),
+        (status = 409, description = "Username or email already taken"),
+        (status = 422, description = "Invalid user", body = ValidationErrorBody),
+    ),
+)]
+#[post("/")]
+async fn create(conn: web::Data<DatabaseConnection>, user_form: Payload<UserForm>, req: HttpRequest) -> impl Responder {
+
+    if let Err(e) = user_form.validate() {
+        return validation_error(e);
+    }
+
+    let username = slugify!(&user_form.username);
+    let email = normalize_email(&user_form.email);
+
+    let existing = User::find()
+        .filter(
+            Condition::any()
+                .add(Expr::expr(Func::lower(Expr::col(entities::user::Column::Username))).eq(username.to_lowercase()))
+                .add(Expr::expr(Func::lower(Expr::col(entities::user::Column::Email))).eq(email.clone())),
+        )
+        .one(conn.as_ref())
+        .await
+        .expect("could not find user");
+
+    if let Some(existing) = existing {
+        let field = if existing.username.eq_ignore_ascii_case(&username) { "username" } else { "email" };
+        return HttpResponse::Conflict().body(format!("a user with that {} already exists", field));
+    }
 
     let hashed_password = hash_password(&user_form.password).unwrap();
 
-    entities::user::ActiveModel {
-        username: Set(slugify!(&user_form.username)),
-        email: Set(user_form.email.clone()),
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    let result = entities::user::ActiveModel {
+        username: Set(username),
+        email: Set(email),
         password: Set(hashed_password),
         is_active: Set(false),
         is_admin: Set(false),
+        totp_enabled: Set(false),
         ..Default::default()
     }
-    .save(conn.as_ref())
-    .await
-    .unwrap();
-
-    HttpResponse::Ok().body(format!("created user: {}", slugify!(&user_form.username)))
+    .insert(&txn)
+    .await;
+
+    match result {
+        Ok(user) => {
+            let view = PrivateUser::from(&user);
+            audit::record(&txn, &Actor::new(&req, None), Entry::new("user.create", "user", user.id).after(&view)).await;
+            txn.commit().await.expect("could not commit transaction");
+
+            HttpResponse::Created()
+                .insert_header((header::LOCATION, format!("/users/{}", user.id)))
+                .json(view)
+        }
+        // another registration won the race between the lookup above and this insert
+        Err(e) if is_unique_violation(&e) => HttpResponse::Conflict().body("a user with that username or email already exists"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("could not create user: {}", e)),
+    }
 }
 
-#[put("/users/{id}")]
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    params(("id" = i32, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "Not implemented yet", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not signed in as an active admin"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[put("/{id}")]
 async fn update(conn: web::Data<DatabaseConnection>, req: HttpRequest) -> impl Responder {
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
-
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+    if let Err(response) = authenticate(conn.as_ref(), &req, true, Some(USERS_WRITE)).await {
+        return response;
     }
-
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
-
-    match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
-    };
     
     HttpResponse::Ok().body("update")
 }
 
-#[delete("/users/{id}")]
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    params(("id" = i32, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "Not implemented yet", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not signed in as an active admin"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[delete("/{id}")]
 async fn delete(conn: web::Data<DatabaseConnection>, req: HttpRequest) -> impl Responder {
 
-    let auth_header = req.headers().get("Authorization").unwrap().to_str().unwrap_or("");
-
-    if !auth_header.starts_with("Bearer ") {
-        return HttpResponse::Unauthorized().body("invalid token");
+    if let Err(response) = authenticate(conn.as_ref(), &req, true, Some(USERS_WRITE)).await {
+        return response;
     }
-
-    let token = auth_header.split(" ").collect::<Vec<&str>>()[1];
-
-    match validate_token(token) {
-        Ok(data) => {
-            if !check_is_valid(conn.as_ref(), data.user_id, true).await {
-                return HttpResponse::Unauthorized().body("You are unauthorized to use this route.");
-            }
-            data
-        },
-        Err(e) => return HttpResponse::Unauthorized().body(format!("could not validate token: {}", e)),
-    };
     
     HttpResponse::Ok().body("delete")
 }
 
-#[post("/users/login")]
-async fn login(conn: web::Data<DatabaseConnection>, login_form: web::Form<LoginForm>) -> HttpResponse {
-
+#[utoipa::path(
+    context_path = "/users",
+    tag = "auth",
+    request_body = LoginForm,
+    responses(
+        (status = 200, description = "An access token, or a `TwoFactorChallenge` when the account has two-factor enabled", body = String),
+        (status = 401, description = "Invalid login or password"),
+        (status = 403, description = "Password login is disabled"),
+        (status = 429, description = "Too many failed attempts, see `Retry-After`"),
+    ),
+)]
+#[post("/login")]
+async fn login(conn: web::Data<DatabaseConnection>, guard: web::Data<LoginGuard>, login_form: Payload<LoginForm>, req: HttpRequest) -> HttpResponse {
+
+    if !password_login_enabled() {
+        return HttpResponse::Forbidden().body("password login is disabled, sign in with single sign-on");
+    }
 
-    let username = login_form.username.clone();
     let password = login_form.password.clone();
     _ = login_form.keep_logged_in;
 
+    let ip = client_ip(&req).map(|ip| ip.to_string());
+    let ip_key = LoginGuard::ip_key(ip.as_deref().unwrap_or("unknown"));
+
+    // checked before touching the database or bcrypt so hammering stays cheap
+    if let Some(wait) = guard.retry_after(&ip_key).await.expect("could not check login attempts") {
+        return too_many_attempts(wait);
+    }
+
+    // normalize the same way `create` does before storing
+    let condition = if login_form.login.contains('@') {
+        Expr::expr(Func::lower(Expr::col(entities::user::Column::Email))).eq(normalize_email(&login_form.login))
+    } else {
+        Expr::expr(Func::lower(Expr::col(entities::user::Column::Username))).eq(slugify!(&login_form.login))
+    };
+
     let user = User::find()
-        .filter(entities::user::Column::Username.eq(username.clone()))
+        .filter(condition)
         .one(conn.as_ref())
         .await
         .unwrap();
 
+    // unknown logins are tracked under the name tried, so they lock out exactly
+    // like real accounts and the 429 doesn't reveal which ones exist
+    let account_key = match &user {
+        Some(user) => LoginGuard::account_key(user.id),
+        None => LoginGuard::unknown_login_key(&login_form.login),
+    };
+
+    if let Some(wait) = guard.retry_after(&account_key).await.expect("could not check login attempts") {
+        return too_many_attempts(wait);
+    }
+
+    // unknown users and wrong passwords get the same response so the endpoint
+    // can't be used to probe which accounts exist
+    let password_matches = match &user {
+        Some(user) => check_password(&password, &user.password),
+        None => {
+            _ = check_password(&password, dummy_hash());
+            false
+        }
+    };
+
+    // the plain password is only around now, so this is when old hashes get upgraded
+    let user = match user {
+        Some(user) if password_matches && needs_rehash(&user.password) => {
+            let mut user: entities::user::ActiveModel = user.into();
+            user.password = Set(hash_password(&password).unwrap());
+            Some(user.update(conn.as_ref()).await.expect("could not update user"))
+        }
+        user => user,
+    };
+
     match user {
-        Some(user) => {
-            if check_password(&password, &user.password).unwrap() {
-                return HttpResponse::Ok().json(create_jwt(user.id, &user.email, 1).unwrap());
-            } else {
-                return HttpResponse::Unauthorized().body("Password is incorrect");
+        // failures are only cleared once the second factor is in too, otherwise a
+        // known password would reset the budget for guessing codes
+        Some(user) if password_matches && user.totp_enabled => HttpResponse::Ok().json(TwoFactorChallenge {
+            two_factor_required: true,
+            token: create_pending_jwt(user.id, &user.email).unwrap(),
+        }),
+        Some(user) if password_matches => {
+            guard.clear(&account_key).await.expect("could not clear login attempts");
+            HttpResponse::Ok().json(create_jwt(user.id, &user.email, 1).unwrap())
+        }
+        _ => {
+            let recorded = async {
+                guard.record_account_failure(&account_key).await?;
+                guard.record_ip_failure(&ip_key).await
+            };
+
+            match recorded.await {
+                Ok(()) => HttpResponse::Unauthorized().body("invalid login or password"),
+                Err(e) => HttpResponse::InternalServerError().body(format!("could not record login attempt: {}", e)),
             }
-        },
-        None => {
-            return HttpResponse::NotFound().body(format!("User {} not found", username));
         }
+    }
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    params(("id" = i32, Path, description = "Id of the user")),
+    responses(
+        (status = 200, description = "Login lockout cleared", body = String, content_type = "text/plain"),
+        (status = 401, description = "Not signed in as an active admin"),
+        (status = 403, description = "API key lacks the `users:write` scope"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+)]
+#[post("/{id}/unlock")]
+async fn unlock(conn: web::Data<DatabaseConnection>, guard: web::Data<LoginGuard>, id: web::Path<i32>, req: HttpRequest) -> impl Responder {
+
+    let admin = match authenticate(conn.as_ref(), &req, true, Some(USERS_WRITE)).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    guard.clear(&LoginGuard::account_key(*id)).await.expect("could not clear login attempts");
+
+    audit::record(conn.as_ref(), &Actor::new(&req, Some(admin.user_id)), Entry::new("user.unlock", "user", *id)).await;
 
+    HttpResponse::Ok().body(format!("unlocked user: {}", id))
+}
+
+#[utoipa::path(
+    context_path = "/users",
+    tag = "users",
+    request_body = ProfileForm,
+    responses(
+        (status = 200, description = "The updated user", body = PrivateUser),
+        (status = 401, description = "Not signed in"),
+        (status = 403, description = "API keys can't edit profiles"),
+        (status = 422, description = "Invalid profile", body = ValidationErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+#[put("/me/profile")]
+async fn update_profile(conn: web::Data<DatabaseConnection>, profile_form: Payload<ProfileForm>, req: HttpRequest) -> impl Responder {
+
+    let identity = match authenticate(conn.as_ref(), &req, false, None).await {
+        Ok(identity) => identity,
+        Err(response) => return response,
+    };
+
+    if let Err(e) = profile_form.validate() {
+        return validation_error(e);
+    }
+
+    let social_links = profile_form
+        .social_links
+        .as_ref()
+        .filter(|links| !links.is_empty())
+        .map(|links| serde_json::to_value(links).unwrap());
+
+    let txn = conn.begin().await.expect("could not start transaction");
+
+    let before = User::find_by_id(identity.user_id).one(&txn).await.expect("could not find user");
+
+    let user = entities::user::ActiveModel {
+        id: Set(identity.user_id),
+        display_name: Set(non_blank(&profile_form.display_name)),
+        bio: Set(non_blank(&profile_form.bio)),
+        website: Set(non_blank(&profile_form.website)),
+        avatar_url: Set(non_blank(&profile_form.avatar_url)),
+        social_links: Set(social_links),
+        ..Default::default()
     }
+    .update(&txn)
+    .await
+    .expect("could not update user");
+    let user = PrivateUser::from(&user);
+
+    let mut entry = Entry::new("user.update_profile", "user", identity.user_id).after(&user);
+    if let Some(before) = &before {
+        entry = entry.before(&PrivateUser::from(before));
+    }
+    audit::record(&txn, &Actor::new(&req, Some(identity.user_id)), entry).await;
+    txn.commit().await.expect("could not commit transaction");
+
+    HttpResponse::Ok().json(user)
 }
 
-#[post("/users/logout")]
+#[utoipa::path(
+    context_path = "/users",
+    tag = "auth",
+    responses((status = 200, description = "Logged out", body = String, content_type = "text/plain")),
+)]
+#[post("/logout")]
 async fn logout() -> HttpResponse {
     HttpResponse::Ok().body("logged out")
 }
@@ -277,5 +648,7 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(update);
     cfg.service(delete);
     cfg.service(login);
+    cfg.service(unlock);
+    cfg.service(update_profile);
     cfg.service(logout);
 }
\ No newline at end of file
@@ -0,0 +1,20 @@
+use actix_web::{web, HttpResponse, get, Responder};
+use actix_web::http::header::{CacheControl, CacheDirective};
+
+use crate::keyring::keyring;
+
+/// Public keys tokens are signed with, for services verifying them on their own.
+#[utoipa::path(
+    tag = "auth",
+    responses((status = 200, description = "A JSON Web Key Set", body = Object)),
+)]
+#[get("/.well-known/jwks.json")]
+async fn jwks() -> impl Responder {
+    HttpResponse::Ok()
+        .insert_header(CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(300)]))
+        .json(keyring().jwks())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(jwks);
+}
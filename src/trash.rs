@@ -0,0 +1,55 @@
+use chrono::{Duration, Utc};
+use sea_orm::*;
+
+use entities::post::Entity as Post;
+
+use crate::audit::{self, Actor, Entry};
+
+/// How often trashed posts past their retention are looked for.
+pub const PURGE_INTERVAL_SECS: u64 = 3600;
+
+/// How long trashed posts can still be restored, from `TRASH_RETENTION_DAYS`.
+pub fn retention() -> Duration {
+    Duration::days(std::env::var("TRASH_RETENTION_DAYS").ok().and_then(|v| v.parse().ok()).unwrap_or(30))
+}
+
+/// Posts purged per transaction.
+const PURGE_BATCH_SIZE: u64 = 100;
+
+/// Permanently deletes posts that have been in the trash for longer than `retention`,
+/// recording each in the audit log, and returns how many were removed.
+pub async fn purge(conn: &DatabaseConnection, retention: Duration) -> Result<u64, DbErr> {
+    let cutoff = Utc::now() - retention;
+    let mut purged = 0;
+
+    loop {
+        let txn = conn.begin().await?;
+
+        // locked until the batch is gone, so a restore can't slip in between the
+        // select and the delete and have a live post purged
+        let posts = Post::find()
+            .filter(entities::post::Column::DeletedAt.lt(cutoff))
+            .order_by_asc(entities::post::Column::Id)
+            .limit(PURGE_BATCH_SIZE)
+            .lock_exclusive()
+            .all(&txn)
+            .await?;
+
+        if posts.is_empty() {
+            return Ok(purged);
+        }
+
+        for post in &posts {
+            audit::record(&txn, &Actor::system(), Entry::new("post.purge", "post", post.id).before(post)).await;
+        }
+
+        let result = Post::delete_many()
+            .filter(entities::post::Column::Id.is_in(posts.iter().map(|post| post.id)))
+            .filter(entities::post::Column::DeletedAt.lt(cutoff))
+            .exec(&txn)
+            .await?;
+
+        txn.commit().await?;
+        purged += result.rows_affected;
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::Cursor;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::QrCode;
+use rand::distributions::Alphanumeric;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Number of recovery codes handed out when two-factor is activated.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generates a new 160-bit TOTP secret, base32 encoded for storage.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+/// Builds the RFC 6238 authenticator (SHA1, 6 digits, 30s steps) for a stored secret.
+pub fn totp(secret: &str, account_name: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_owned()).to_bytes().map_err(|e| format!("{:?}", e))?;
+    let issuer = std::env::var("TOTP_ISSUER").unwrap_or("Blog API".to_string());
+
+    TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(issuer), account_name.to_owned()).map_err(|e| e.to_string())
+}
+
+/// Checks a code against the current time step, allowing one step of clock skew, and
+/// returns the step it belongs to so callers can refuse to accept that step again.
+pub fn check_code(secret: &str, account_name: &str, code: &str) -> Option<i64> {
+    let mut totp = totp(secret, account_name).ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let current = now / totp.step;
+
+    // each step is checked on its own, to know which one matched
+    totp.skew = 0;
+    (current - 1..=current + 1)
+        .find(|step| totp.check(code.trim(), step * totp.step))
+        .map(|step| step as i64)
+}
+
+/// Renders an `otpauth://` URI as a PNG QR code.
+pub fn qr_png(uri: &str) -> Result<Vec<u8>, String> {
+    let code = QrCode::new(uri.as_bytes()).map_err(|e| e.to_string())?;
+    let image = code.render::<Luma<u8>>().min_dimensions(256, 256).build();
+
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .map_err(|e| e.to_string())?;
+
+    Ok(png)
+}
+
+/// Generates one-time recovery codes formatted as `xxxxx-xxxxx`.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes carry enough entropy that a plain SHA-256 is sufficient, which
+/// also lets them be looked up by hash.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
@@ -0,0 +1,133 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use actix_web::HttpResponse;
+use serde::Serialize;
+use utoipa::ToSchema;
+use validator::{ValidationError, ValidationErrors};
+
+use slugify::slugify;
+
+/// Usernames that would collide with routes or be mistaken for staff accounts.
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "api", "login", "logout", "me", "moderator", "posts", "root", "support", "system", "users",
+];
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FieldError {
+    code: String,
+    message: String,
+}
+
+/// `422` body listing what is wrong with each invalid field.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ValidationErrorBody {
+    /// Each invalid field mapped to a list of `FieldError`s.
+    #[schema(value_type = Object)]
+    errors: BTreeMap<&'static str, Vec<FieldError>>,
+}
+
+impl From<ValidationErrors> for ValidationErrorBody {
+    fn from(errors: ValidationErrors) -> Self {
+        let errors = errors
+            .field_errors()
+            .into_iter()
+            .map(|(field, errors)| {
+                let errors = errors
+                    .iter()
+                    .map(|e| FieldError {
+                        code: e.code.to_string(),
+                        message: e.message.clone().unwrap_or_else(|| e.code.clone()).to_string(),
+                    })
+                    .collect();
+                (field, errors)
+            })
+            .collect();
+
+        ValidationErrorBody { errors }
+    }
+}
+
+/// Turns validator errors into a `422 Unprocessable Entity` with one entry per invalid field.
+pub fn validation_error(errors: ValidationErrors) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(ValidationErrorBody::from(errors))
+}
+
+fn error(code: &'static str, message: &'static str) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(Cow::from(message));
+    error
+}
+
+/// Requires at least three of: lowercase, uppercase, digits and symbols.
+pub fn validate_password_strength(password: &str) -> Result<(), ValidationError> {
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_ascii_digit()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+
+    if classes.iter().filter(|&&present| present).count() < 3 {
+        return Err(error("password_strength", "password must mix at least three of lowercase, uppercase, digits and symbols"));
+    }
+
+    Ok(())
+}
+
+/// Slugs may only contain lowercase letters, digits and single dashes between them.
+pub fn validate_slug(slug: &str) -> Result<(), ValidationError> {
+    let valid = !slug.is_empty()
+        && slug.split('-').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+
+    if !valid {
+        return Err(error("slug_charset", "slug may only contain lowercase letters, digits and single dashes"));
+    }
+
+    Ok(())
+}
+
+/// Checks the username as it will be stored, i.e. after slugification.
+pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    let slug = slugify!(username);
+
+    if slug.is_empty() {
+        return Err(error("username_charset", "username must contain at least one letter or digit"));
+    }
+
+    if RESERVED_USERNAMES.contains(&slug.as_str()) {
+        return Err(error("username_reserved", "username is reserved"));
+    }
+
+    Ok(())
+}
+
+/// Only `http` and `https` links, so profiles can't carry `javascript:` URLs.
+pub fn validate_http_url(url: &str) -> Result<(), ValidationError> {
+    let http = url.starts_with("https://") || url.starts_with("http://");
+
+    if !http || !validator::validate_url(url) {
+        return Err(error("url", "must be an http or https url"));
+    }
+
+    Ok(())
+}
+
+/// Up to ten links keyed by network name, e.g. `{"github": "https://github.com/..."}`.
+pub fn validate_social_links(links: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    if links.len() > 10 {
+        return Err(error("social_links_count", "at most 10 social links are allowed"));
+    }
+
+    for (network, url) in links {
+        if network.len() > 32 || validate_slug(network).is_err() {
+            return Err(error("social_links_network", "network names may only contain lowercase letters, digits and single dashes"));
+        }
+
+        if url.len() > 2048 || validate_http_url(url).is_err() {
+            return Err(error("social_links_url", "social links must be http or https urls"));
+        }
+    }
+
+    Ok(())
+}